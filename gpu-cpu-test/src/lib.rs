@@ -5,33 +5,42 @@ mod tests {
     use paired::bls12_381::{Fr, FrRepr};
     use rand::{thread_rng, Rng};
     use rust_fil_nse_gpu::*;
+    use std::convert::TryFrom;
     use storage_proofs::cache_key::CacheKey;
-    use storage_proofs::hasher::poseidon;
+    use storage_proofs::hasher::{poseidon, Sha256Hasher};
     use storage_proofs::merkle::split_config;
-    use storage_proofs::merkle::OctLCMerkleTree;
+    use storage_proofs::merkle::{create_base_merkle_tree, BinaryMerkleTree, OctLCMerkleTree};
     use storage_proofs::porep::nse;
+    use storage_proofs::porep::stacked::generate_replica_id;
 
-    pub const TEST_CONFIG: Config = Config {
-        k: 2,
-        num_nodes_window: 512, // Must be 2^(3*x) for 8-ary merkle trees
-        degree_expander: 56,
-        degree_butterfly: 4,
-        num_expander_layers: 4,
-        num_butterfly_layers: 3,
-    };
-
-    fn to_cpu_config(conf: Config) -> nse::Config {
+    // The CPU implementation only labels with SHA-256, and only has the default layer
+    // schedule, so the GPU config is derived from its config
+    fn cpu_config() -> nse::Config {
         nse::Config {
-            k: conf.k,
-            num_nodes_window: conf.num_nodes_window,
-            degree_expander: conf.degree_expander,
-            degree_butterfly: conf.degree_butterfly,
-            num_expander_layers: conf.num_expander_layers,
-            num_butterfly_layers: conf.num_butterfly_layers,
+            k: 2,
+            num_nodes_window: 512, // Must be 2^(3*x) for 8-ary merkle trees
+            degree_expander: 56,
+            degree_butterfly: 4,
+            num_expander_layers: 4,
+            num_butterfly_layers: 3,
             sector_size: 0,
         }
     }
 
+    fn test_config() -> Config {
+        let conf = cpu_config();
+        Config::new(
+            conf.k,
+            conf.num_nodes_window,
+            conf.degree_expander,
+            conf.degree_butterfly,
+            conf.num_expander_layers,
+            conf.num_butterfly_layers,
+            LabelingHash::Sha256,
+        )
+        .unwrap()
+    }
+
     fn replica_id_to_poseidon_domain(replica_id: ReplicaId) -> poseidon::PoseidonDomain {
         unsafe { std::mem::transmute::<ReplicaId, poseidon::PoseidonDomain>(replica_id) }
     }
@@ -51,13 +60,13 @@ mod tests {
     #[test]
     fn test_expander_compatibility() {
         let mut rng = thread_rng();
-        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
-        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
 
         for _ in 0..10 {
-            let prev_layer = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
+            let prev_layer = Layer::random(&mut rng, test_config().num_nodes_window);
             let replica_id = ReplicaId::random(&mut rng);
-            let window_index: usize = rng.gen();
+            let window_index: u64 = rng.gen::<u32>() as u64;
             let layer_index = 2;
 
             gpu.push_layer(&prev_layer).unwrap();
@@ -65,10 +74,10 @@ mod tests {
                 .generate_expander_layer(replica_id, window_index, layer_index)
                 .unwrap();
 
-            let layer_a = Vec::<u8>::from(&prev_layer);
+            let layer_a = Vec::<u8>::from(&OrdinaryLayer::from(&prev_layer));
             let mut layer_b = layer_a.clone();
             nse::expander_layer(
-                &to_cpu_config(TEST_CONFIG),
+                &cpu_config(),
                 window_index as u32,
                 &replica_id_to_poseidon_domain(replica_id),
                 layer_index as u32,
@@ -76,7 +85,8 @@ mod tests {
                 &mut layer_b,
             )
             .unwrap();
-            let cpu_output = Layer::from(&layer_b);
+            let cpu_output =
+                Layer::try_from(&OrdinaryLayer::try_from(&layer_b[..]).unwrap()).unwrap();
 
             assert_eq!(accumulate(&cpu_output.0), accumulate(&gpu_output.0));
         }
@@ -85,13 +95,13 @@ mod tests {
     #[test]
     fn test_butterfly_compatibility() {
         let mut rng = thread_rng();
-        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
-        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
 
         for _ in 0..10 {
-            let prev_layer = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
+            let prev_layer = Layer::random(&mut rng, test_config().num_nodes_window);
             let replica_id = ReplicaId::random(&mut rng);
-            let window_index: usize = rng.gen();
+            let window_index: u64 = rng.gen::<u32>() as u64;
             let layer_index = 5;
 
             gpu.push_layer(&prev_layer).unwrap();
@@ -99,10 +109,10 @@ mod tests {
                 .generate_butterfly_layer(replica_id, window_index, layer_index)
                 .unwrap();
 
-            let layer_a = Vec::<u8>::from(&prev_layer);
+            let layer_a = Vec::<u8>::from(&OrdinaryLayer::from(&prev_layer));
             let mut layer_b = layer_a.clone();
             nse::butterfly_layer(
-                &to_cpu_config(TEST_CONFIG),
+                &cpu_config(),
                 window_index as u32,
                 &replica_id_to_poseidon_domain(replica_id),
                 layer_index as u32,
@@ -110,7 +120,8 @@ mod tests {
                 &mut layer_b,
             )
             .unwrap();
-            let cpu_output = Layer::from(&layer_b);
+            let cpu_output =
+                Layer::try_from(&OrdinaryLayer::try_from(&layer_b[..]).unwrap()).unwrap();
 
             assert_eq!(accumulate(&cpu_output.0), accumulate(&gpu_output.0));
         }
@@ -119,16 +130,16 @@ mod tests {
     #[test]
     fn test_sealer_compatibility() {
         let mut rng = thread_rng();
-        let ctx =
-            GPUContext::default(TEST_CONFIG, TreeOptions::Enabled { rows_to_discard: 2 }).unwrap();
-        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let ctx = GPUContext::default(test_config(), TreeOptions::Enabled { rows_to_discard: 2 })
+            .unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
 
         for _ in 0..10 {
-            let data = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
+            let data = Layer::random(&mut rng, test_config().num_nodes_window);
             let replica_id = ReplicaId::random(&mut rng);
-            let window_index: usize = rng.gen();
+            let window_index: u64 = rng.gen::<u32>() as u64;
             let sealer = Sealer::new(
-                TEST_CONFIG,
+                test_config(),
                 SealerInput {
                     replica_id,
                     window_index,
@@ -149,7 +160,7 @@ mod tests {
                 })
                 .collect::<Vec<_>>();
 
-            let cpu_config = to_cpu_config(TEST_CONFIG);
+            let cpu_config = cpu_config();
             let cache_dir = tempfile::tempdir().unwrap();
             let store_config = StoreConfig::new(
                 cache_dir.path(),
@@ -158,7 +169,7 @@ mod tests {
             );
             let store_configs =
                 split_config(store_config.clone(), cpu_config.num_layers()).unwrap();
-            let mut cpu_output = Vec::<u8>::from(&data);
+            let mut cpu_output = Vec::<u8>::from(&OrdinaryLayer::from(&data));
             let (cpu_trees, cpu_replica_tree) =
                 nse::encode_with_trees::<OctLCMerkleTree<poseidon::PoseidonHasher>>(
                     &cpu_config,
@@ -168,7 +179,8 @@ mod tests {
                     &mut cpu_output,
                 )
                 .unwrap();
-            let cpu_output = Layer::from(&cpu_output);
+            let cpu_output =
+                Layer::try_from(&OrdinaryLayer::try_from(&cpu_output[..]).unwrap()).unwrap();
             let cpu_roots = {
                 let mut roots = cpu_trees.iter().map(|t| t.root()).collect::<Vec<_>>();
                 roots.push(cpu_replica_tree.root());
@@ -179,4 +191,99 @@ mod tests {
             assert_eq!(cpu_roots, gpu_roots);
         }
     }
+
+    #[test]
+    fn test_commitment_compatibility() {
+        let mut rng = thread_rng();
+        let ctx = GPUContext::default(test_config(), TreeOptions::Enabled { rows_to_discard: 2 })
+            .unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+
+        // `comm_d` over a sector of several windows
+        let windows = (0..4)
+            .map(|_| Layer::random(&mut rng, test_config().num_nodes_window))
+            .collect::<Vec<_>>();
+        let data = windows
+            .iter()
+            .flat_map(|w| Vec::<u8>::from(&OrdinaryLayer::from(w)))
+            .collect::<Vec<_>>();
+        let data_tree = create_base_merkle_tree::<BinaryMerkleTree<Sha256Hasher>>(
+            None,
+            4 * test_config().num_nodes_window,
+            &data,
+        )
+        .unwrap();
+        assert_eq!(
+            &comm_d(&windows).unwrap().to_bytes()[..],
+            data_tree.root().as_ref()
+        );
+
+        // `comm_r` of a single-window sector, whose layer commitments are the window roots
+        let replica_id = ReplicaId::random(&mut rng);
+        let sealer = Sealer::new(
+            test_config(),
+            SealerInput {
+                replica_id,
+                window_index: 0,
+                original_data: windows[0].clone(),
+            },
+            &mut gpu,
+            true,
+        )
+        .unwrap();
+        let gpu_roots = sealer.map(|r| r.unwrap().tree[0]).collect::<Vec<_>>();
+
+        let cpu_config = cpu_config();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let store_config = StoreConfig::new(
+            cache_dir.path(),
+            CacheKey::CommDTree.to_string(),
+            StoreConfig::default_rows_to_discard(cpu_config.num_nodes_window as usize, 8),
+        );
+        let store_configs = split_config(store_config, cpu_config.num_layers()).unwrap();
+        let mut cpu_output = Vec::<u8>::from(&OrdinaryLayer::from(&windows[0]));
+        let (cpu_trees, cpu_replica_tree) =
+            nse::encode_with_trees::<OctLCMerkleTree<poseidon::PoseidonHasher>>(
+                &cpu_config,
+                store_configs,
+                0,
+                &replica_id_to_poseidon_domain(replica_id),
+                &mut cpu_output,
+            )
+            .unwrap();
+        let comm_layers = cpu_trees.iter().map(|t| t.root()).collect::<Vec<_>>();
+        let cpu_comm_r = nse::hash_comm_r(&comm_layers, cpu_replica_tree.root());
+
+        let shape = SectorTreeShape {
+            sub_tree_arity: 0,
+            top_tree_arity: 0,
+        };
+        assert_eq!(
+            node_to_poseidon_domain(comm_r(&[gpu_roots], shape).unwrap()),
+            cpu_comm_r.into()
+        );
+    }
+
+    #[test]
+    fn test_replica_id_compatibility() {
+        let mut rng = thread_rng();
+        for _ in 0..10 {
+            let prover_id: [u8; 32] = rng.gen();
+            let sector_id: u64 = rng.gen();
+            let ticket: [u8; 32] = rng.gen();
+            let comm_d = Node::random(&mut rng);
+            let porep_seed: [u8; 32] = rng.gen();
+
+            let replica_id = ReplicaId::derive(&prover_id, sector_id, &ticket, comm_d, &porep_seed);
+            let cpu_replica_id = generate_replica_id::<poseidon::PoseidonHasher, _>(
+                &prover_id,
+                sector_id,
+                &ticket,
+                comm_d.to_bytes(),
+                &porep_seed,
+            );
+
+            assert_eq!(replica_id_to_poseidon_domain(replica_id), cpu_replica_id);
+        }
+    }
 }
@@ -1,8 +1,11 @@
 use rand::{thread_rng, Rng};
 use rust_fil_nse_gpu::*;
+use std::convert::TryFrom;
 use std::time::Instant;
 use structopt::StructOpt;
 
+const TUNING_FILE: &str = "nse-tuning.tsv";
+
 macro_rules! timer {
     ($e:expr, $samples:expr) => {{
         let before = Instant::now();
@@ -14,44 +17,52 @@ macro_rules! timer {
     }};
 }
 
+fn random_window_index(num_nodes_window: usize) -> u64 {
+    thread_rng().gen_range(0, u64::max_value() / num_nodes_window as u64)
+}
+
 fn bench_mask(gpu: &mut GPU, samples: usize) -> u64 {
     let mut rng = thread_rng();
     let replica_id = ReplicaId::random(&mut rng);
-    let window_index: usize = rng.gen();
+    let window_index = random_window_index(gpu.leaf_count());
     timer!(
         gpu.generate_mask_layer(replica_id, window_index).unwrap(),
         samples
     )
 }
 
-fn bench_expander(gpu: &mut GPU, samples: usize) -> u64 {
+// `None` if the layer schedule has no expander layer
+fn bench_expander(gpu: &mut GPU, samples: usize) -> Option<u64> {
     let mut rng = thread_rng();
     let replica_id = ReplicaId::random(&mut rng);
-    let window_index: usize = rng.gen();
+    let window_index = random_window_index(gpu.leaf_count());
+    let layer_index = gpu.config.layer_position(LayerIndex::Expander(1)).ok()?;
     gpu.generate_mask_layer(replica_id, window_index).unwrap();
-    timer!(
-        gpu.generate_expander_layer(replica_id, window_index, rng.gen())
+    Some(timer!(
+        gpu.generate_expander_layer(replica_id, window_index, layer_index)
             .unwrap(),
         samples
-    )
+    ))
 }
 
-fn bench_butterfly(gpu: &mut GPU, samples: usize) -> u64 {
+// `None` if the layer schedule has no butterfly layer
+fn bench_butterfly(gpu: &mut GPU, samples: usize) -> Option<u64> {
     let mut rng = thread_rng();
     let replica_id = ReplicaId::random(&mut rng);
-    let window_index: usize = rng.gen();
+    let window_index = random_window_index(gpu.leaf_count());
+    let layer_index = gpu.config.layer_position(LayerIndex::Butterfly(1)).ok()?;
     gpu.generate_mask_layer(replica_id, window_index).unwrap();
-    timer!(
-        gpu.generate_butterfly_layer(replica_id, window_index, rng.gen())
+    Some(timer!(
+        gpu.generate_butterfly_layer(replica_id, window_index, layer_index)
             .unwrap(),
         samples
-    )
+    ))
 }
 
 fn bench_combine(gpu: &mut GPU, samples: usize) -> u64 {
     let mut rng = thread_rng();
     let replica_id = ReplicaId::random(&mut rng);
-    let window_index: usize = rng.gen();
+    let window_index = random_window_index(gpu.leaf_count());
     let data = Layer::random(&mut rng, gpu.leaf_count());
     gpu.generate_mask_layer(replica_id, window_index).unwrap();
     timer!(gpu.combine_layer(&data, false).unwrap(), samples)
@@ -68,7 +79,7 @@ fn bench_sealer(
     let inputs: Vec<SealerInput> = (0..num_windows)
         .map(|_| SealerInput {
             replica_id: ReplicaId::random(&mut rng),
-            window_index: rng.gen(),
+            window_index: random_window_index(config.num_nodes_window),
             original_data: Layer::random(&mut rng, config.num_nodes_window),
         })
         .collect();
@@ -89,7 +100,7 @@ fn bench_sealer(
     )
 }
 
-#[derive(Debug, StructOpt, Clone, Copy)]
+#[derive(Debug, StructOpt, Clone)]
 #[structopt(name = "NSE Bench", about = "Benchmarking NSE operations on GPU.")]
 struct Opts {
     #[structopt(short = "k", default_value = "8")]
@@ -104,6 +115,10 @@ struct Opts {
     num_expander_layers: usize,
     #[structopt(long = "num-butterfly-layers", default_value = "7")]
     num_butterfly_layers: usize,
+    /// Comma-separated layer schedule (E.g. `M,E384,B16`), overriding the degrees and
+    /// numbers of layers above.
+    #[structopt(long = "schedule", use_delimiter = true)]
+    schedule: Vec<LayerSpec>,
     #[structopt(long = "samples", default_value = "10")]
     samples: usize,
     #[structopt(long = "sealer")]
@@ -112,17 +127,45 @@ struct Opts {
     num_windows: usize,
     #[structopt(long = "trees")]
     build_trees: bool,
+    #[structopt(long = "cpu-trees")]
+    cpu_trees: bool,
+    #[structopt(long = "tree-device")]
+    tree_device: Option<usize>,
+    #[structopt(long = "tune")]
+    tune: bool,
+    #[structopt(long = "profile")]
+    profile: bool,
+    #[structopt(long = "pinned")]
+    pinned: bool,
+    #[structopt(long = "blake2s")]
+    blake2s: bool,
+    /// Number of nodes whose expander bit-streams are generated at once (Defaults to the
+    /// largest power of 2 whose bit-streams fit in 128MB).
+    #[structopt(long = "stream-tile-size")]
+    stream_tile_size: Option<usize>,
 }
 
-impl From<Opts> for Config {
-    fn from(cli: Opts) -> Self {
-        Config {
-            k: cli.k,
-            num_nodes_window: cli.num_nodes_window,
-            degree_expander: cli.degree_expander,
-            degree_butterfly: cli.degree_butterfly,
-            num_expander_layers: cli.num_expander_layers,
-            num_butterfly_layers: cli.num_butterfly_layers,
+impl TryFrom<&Opts> for Config {
+    type Error = NSEError;
+
+    fn try_from(cli: &Opts) -> NSEResult<Self> {
+        let hash = if cli.blake2s {
+            LabelingHash::Blake2s
+        } else {
+            LabelingHash::Sha256
+        };
+        if cli.schedule.is_empty() {
+            Config::new(
+                cli.k,
+                cli.num_nodes_window,
+                cli.degree_expander,
+                cli.degree_butterfly,
+                cli.num_expander_layers,
+                cli.num_butterfly_layers,
+                hash,
+            )
+        } else {
+            Config::with_schedule(cli.k, cli.num_nodes_window, &cli.schedule, hash)
         }
     }
 }
@@ -133,8 +176,26 @@ fn main() {
     let opts = Opts::from_args();
     println!("Options: {:?}", opts);
 
-    let config: Config = Config::from(opts);
-    let tree_options = if opts.build_trees {
+    let config = Config::try_from(&opts).unwrap();
+    let tree_options = if opts.cpu_trees {
+        TreeOptions::EnabledCPU { rows_to_discard: 2 }
+    } else if let Some(i) = opts.tree_device {
+        let devices = utils::all_devices().unwrap();
+        match devices.get(i) {
+            Some(&device) => TreeOptions::EnabledOnDevice {
+                rows_to_discard: 2,
+                device,
+            },
+            None => {
+                eprintln!(
+                    "Invalid tree device {}, only {} devices are available!",
+                    i,
+                    devices.len()
+                );
+                std::process::exit(1);
+            }
+        }
+    } else if opts.build_trees {
         TreeOptions::Enabled { rows_to_discard: 2 }
     } else {
         TreeOptions::Disabled
@@ -146,12 +207,35 @@ fn main() {
             bench_sealer(config, opts.samples, tree_options, opts.num_windows)
         );
     } else {
-        let ctx = GPUContext::default(config, tree_options).unwrap();
+        let device = utils::default_device().unwrap();
+        let mut ctx = if opts.profile {
+            GPUContext::new_profiled(device, config.clone(), tree_options).unwrap()
+        } else {
+            GPUContext::new(device, config.clone(), tree_options).unwrap()
+        };
+        ctx.set_pinned_transfers(opts.pinned).unwrap();
         let mut gpu = GPU::new(ctx, config).unwrap();
+        if let Some(tile_size) = opts.stream_tile_size {
+            gpu.set_stream_tile_size(tile_size).unwrap();
+        }
+        println!("Stream tile size: {}", gpu.stream_tile_size());
+        if opts.tune {
+            load_or_tune(&mut gpu, TUNING_FILE, opts.samples).unwrap();
+            println!("Local work sizes: {:?}", gpu.context().local_work_sizes());
+        }
 
         println!("Mask: {}ms", bench_mask(&mut gpu, opts.samples));
-        println!("Expander: {}ms", bench_expander(&mut gpu, opts.samples));
-        println!("Butterfly: {}ms", bench_butterfly(&mut gpu, opts.samples));
+        match bench_expander(&mut gpu, opts.samples) {
+            Some(ms) => println!("Expander: {}ms", ms),
+            None => println!("Expander: No expander layer in schedule"),
+        }
+        match bench_butterfly(&mut gpu, opts.samples) {
+            Some(ms) => println!("Butterfly: {}ms", ms),
+            None => println!("Butterfly: No butterfly layer in schedule"),
+        }
         println!("Combine: {}ms", bench_combine(&mut gpu, opts.samples));
+        if let Some(report) = gpu.profile_report().unwrap() {
+            println!("{}", report);
+        }
     }
 }
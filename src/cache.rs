@@ -0,0 +1,110 @@
+use crate::{Config, Layer, Node, ReplicaId};
+use ocl::Buffer;
+use std::collections::VecDeque;
+
+/// Identifies the finalized key layer of a window.
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct KeyId {
+    pub replica_id: ReplicaId,
+    pub window_index: u64,
+    pub config: Config,
+}
+
+// Least-recently-used cache, most recently used entries are kept in front.
+// A capacity of 0 disables the cache.
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+    entries: VecDeque<(K, V)>,
+}
+
+impl<K: PartialEq, V> LruCache<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries.truncate(capacity);
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub(crate) fn contains(&self, key: &K) -> bool {
+        self.entries.iter().any(|(k, _)| k == key)
+    }
+
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        let pos = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(pos).unwrap();
+        self.entries.push_front(entry);
+        self.entries.front().map(|(_, v)| v)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(pos) = self.entries.iter().position(|(k, _)| *k == key) {
+            self.entries.remove(pos);
+        }
+        self.entries.truncate(self.capacity - 1);
+        self.entries.push_front((key, value));
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Finalized key layers of recently unsealed windows, kept both on device and on host.
+pub struct KeyCache {
+    pub(crate) device: LruCache<KeyId, Buffer<Node>>,
+    pub(crate) host: LruCache<KeyId, Layer>,
+}
+
+impl KeyCache {
+    pub(crate) fn new(device_capacity: usize, host_capacity: usize) -> Self {
+        Self {
+            device: LruCache::new(device_capacity),
+            host: LruCache::new(host_capacity),
+        }
+    }
+
+    pub fn contains(&self, id: &KeyId) -> bool {
+        self.device.contains(id) || self.host.contains(id)
+    }
+
+    /// Number of keys cached on device and on host.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.device.len(), self.host.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_cache() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        assert_eq!(cache.get(&1), Some(&"a"));
+        cache.insert(3, "c"); // Evicts 2, as 1 is used more recently
+        assert!(!cache.contains(&2));
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.get(&3), Some(&"c"));
+        cache.insert(3, "d");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&3), Some(&"d"));
+
+        cache.set_capacity(0);
+        cache.insert(4, "e");
+        assert_eq!(cache.len(), 0);
+    }
+}
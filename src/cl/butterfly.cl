@@ -1,28 +1,50 @@
-__kernel void generate_butterfly(__global Fr *input,
-                                 __global Fr *output,
-                                 replica_id id,
-                                 uint window_index,
-                                 uint layer_index) {
+Fr butterfly_node(__global Fr *input,
+                  replica_id id,
+                  ulong window_index,
+                  uint layer_index,
+                  uint degree,
+                  uint factor,
+                  uint v) {
 
-  uint v = get_global_id(0); // Nodes are processed in parallel
-  ulong node_absolute_index = (ulong)window_index * N + v;
+  ulong node_absolute_index = window_index * N + v;
 
-  uint factor = 1 << (LOG2_DEGREE_BUTTERFLY * (NUM_LAYERS - layer_index));
+  label_state state = label_init();
+  state = label_update(state, hash_prefix(layer_index, node_absolute_index, id));
 
-  sha256_domain state = sha256_INIT;
-  state = sha256_update(state, hash_prefix(layer_index, node_absolute_index, id));
-
-  for(uint i = 0; i < DEGREE_BUTTERFLY / 2; i++) {
+  for(uint i = 0; i < degree / 2; i++) {
     uint i_1 = i * 2;
     uint i_2 = i * 2 + 1;
 
     uint parent_1 = (v + i_1 * factor) & MODULO_N_MASK;
     uint parent_2 = (v + i_2 * factor) & MODULO_N_MASK;
 
-    state = sha256_update(state, Fr_to_sha256_block(input[parent_1], input[parent_2]));
+    state = label_update(state, Fr_to_label_block(input[parent_1], input[parent_2]));
   }
 
-  state = sha256_finish(state, DEGREE_BUTTERFLY / 2 + 1);
+  return label_domain_to_Fr(label_finish(state, degree / 2 + 1));
+}
+
+__kernel void generate_butterfly(__global Fr *input,
+                                 __global Fr *output,
+                                 replica_id id,
+                                 ulong window_index,
+                                 uint layer_index,
+                                 uint degree,
+                                 uint factor) {
+
+  uint v = get_global_id(0); // Nodes are processed in parallel
+  output[v] = butterfly_node(input, id, window_index, layer_index, degree, factor, v);
+}
 
-  output[v] = sha256_domain_to_Fr(state);
+__kernel void generate_butterfly_batch(__global Fr *input,
+                                       __global Fr *output,
+                                       __global replica_id *ids,
+                                       __global ulong *window_indices,
+                                       uint layer_index,
+                                       uint degree,
+                                       uint factor) {
+
+  size_t i = get_global_id(0);
+  uint w = i / N;
+  output[i] = butterfly_node(input + (size_t)w * N, ids[w], window_indices[w], layer_index, degree, factor, i % N);
 }
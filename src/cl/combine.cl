@@ -1,16 +1,23 @@
+// Launched on the nodes of the segment only, with a global offset equal to
+// the offset of the segment. `data` only holds the segment.
 __kernel void combine_segment(__global Fr *mask,
                               __global Fr *data,
-                              uint offset,
-                              uint len,
                               uint is_decode) {
-  uint node = get_global_id(0); // Nodes are processed in parallel
-
-  // TODO: Delete this in future, and limit global work size
-  if(node < offset || node >= offset + len)
-    return;
+  size_t node = get_global_id(0); // Nodes are processed in parallel
+  size_t i = node - get_global_offset(0); // Index of node in segment
 
   if(is_decode)
-    data[node] = Fr_sub(data[node], mask[node]);
+    data[i] = Fr_sub(data[i], mask[node]);
   else
-    data[node] = Fr_add(data[node], mask[node]);
+    data[i] = Fr_add(data[i], mask[node]);
+}
+
+// Same as `combine_segment`, decodes `data` with `from_mask` and encodes it with `to_mask`.
+__kernel void recombine_segment(__global Fr *from_mask,
+                                __global Fr *to_mask,
+                                __global Fr *data) {
+  size_t node = get_global_id(0); // Nodes are processed in parallel
+  size_t i = node - get_global_offset(0); // Index of node in segment
+
+  data[i] = Fr_add(Fr_sub(data[i], from_mask[node]), to_mask[node]);
 }
@@ -1,16 +1,10 @@
 #define BITS_PER_BYTE (8)
-#define NUM_LAYERS (NUM_EXPANDER_LAYERS + NUM_BUTTERFLY_LAYERS)
 #define MODULO_N_MASK (N - 1)
 
 typedef struct {
   uint vals[8];
 } replica_id;
 
-__kernel void to_montgomery(__global Fr *buffer) {
-  uint node = get_global_id(0);
-  buffer[node] = Fr_mont(buffer[node]);
-}
-
 __kernel void generate_montgomery(__global Fr *input,
                                   __global Fr *output) {
   uint node = get_global_id(0);
@@ -28,8 +22,8 @@ uint reverse_bytes(uint a) {
          (a & 0x00ff0000) >> 8 | (a & 0xff000000) >> 24;
 }
 
-sha256_block hash_prefix(uint layer_index, ulong node_absolute_index, replica_id id) {
-  sha256_block data = sha256_ZERO;
+label_block hash_prefix(uint layer_index, ulong node_absolute_index, replica_id id) {
+  label_block data = label_ZERO;
   data.vals[0] = layer_index;
   // `node_absolute_index` is encoded in big-endian order (Based on the cpu impl),
   // which means (Based on the assumption that Nvidia/AMD devices are little-endian)
@@ -43,8 +37,8 @@ sha256_block hash_prefix(uint layer_index, ulong node_absolute_index, replica_id
   return data;
 }
 
-sha256_block Fr_to_sha256_block(Fr a, Fr b) {
-  sha256_block data;
+label_block Fr_to_label_block(Fr a, Fr b) {
+  label_block data;
   for(uint i = 0; i < Fr_LIMBS; i++) {
     data.vals[2 * i] = reverse_bytes(a.val[i] & 0xffffffff);
     data.vals[2 * i + 1] = reverse_bytes(a.val[i] >> 32);
@@ -54,7 +48,7 @@ sha256_block Fr_to_sha256_block(Fr a, Fr b) {
   return data;
 }
 
-Fr sha256_domain_to_Fr(sha256_domain state) {
+Fr label_domain_to_Fr(label_domain state) {
   Fr f;
   for(uint i = 0; i < Fr_LIMBS; i++)
     f.val[i] = ((limb)reverse_bytes(state.vals[2 * i + 1]) << 32) + reverse_bytes(state.vals[2 * i]);
@@ -1,42 +1,48 @@
 #define BYTE_SIZE (BIT_SIZE / 8)
 
-// We are going to generate and store the entire bit-stream per node
-// before running the expander algorithm. Is this a good idea?
-// (bit-stream per node is around ~1KB in size)
-typedef struct {
-  sha256_domain bit_source[STREAM_HASH_COUNT];
-} bit_stream;
-
-bit_stream gen_stream(uint node) {
-  bit_stream stream;
+// Bit-streams of nodes (~1KB per node) only depend on the node index, so they
+// are generated into a global buffer, shared by all expander layers and windows,
+// instead of being kept in private memory of every work item. They are always
+// derived with SHA-256, whatever the labeling hash is.
+//
+// Streams are generated for a tile of `tile_len` nodes at a time, and stored
+// word-major (Word `w` of the stream of the `n`th node of the tile is at
+// `w * tile_len + n`), so that work items reading the same word of their streams
+// read consecutive words.
+__kernel void generate_streams(__global uint *streams, uint tile_offset) {
+  uint n = get_global_id(0); // Nodes of the tile are processed in parallel
+  uint tile_len = get_global_size(0);
   sha256_block data = sha256_ZERO;
-  data.vals[0] = node;
+  data.vals[0] = tile_offset + n;
   for(uint i = 0; i < STREAM_HASH_COUNT; i++) {
     data.vals[1] = i;
-    stream.bit_source[i] = sha256(data);
+    sha256_domain hash = sha256(data);
+    for(uint j = 0; j < 8; j++)
+      streams[(i * 8 + j) * tile_len + n] = hash.vals[j];
   }
-  return stream;
 }
 
-uchar get_byte(bit_stream *stream, uint i) {
-  return ((uchar*)stream)[i ^ 3]; // `i ^ 3` => Change endianness of uint bytes
+// Get `i`th byte of the stream of `n`th node of the tile
+uint get_byte(__global uint *streams, uint tile_len, uint n, uint i) {
+  uint word = streams[(i >> 2) * tile_len + n];
+  return (word >> ((3 - (i & 3)) * BITS_PER_BYTE)) & 0xff; // Bytes of words are big-endian
 }
 
 // Get `i`th chunk of bitstream (chunks are `BIT_SIZE` long)
 // I.e. get `i`th *non-expanded* parent of node
 // Result is in the range `[0, 2^BIT_SIZE)`
-uint get_parent(bit_stream *stream, uint i) {
+uint get_parent(__global uint *streams, uint tile_len, uint n, uint i) {
   uint ret = 0;
   for(uint j = 0; j < BYTE_SIZE; j++) {
-    uint bt = get_byte(stream, i * BYTE_SIZE + j);
+    uint bt = get_byte(streams, tile_len, n, i * BYTE_SIZE + j);
     ret |= (bt << (j * BITS_PER_BYTE));
   }
   return ret;
 }
 
 // Returns `i`th *expanded* parent of node
-// `i` is in the range `[0, K * EXPANDED_DEGREE)`
-uint get_expanded_parent(bit_stream *stream, uint i) {
+// `i` is in the range `[0, K * degree)`
+uint get_expanded_parent(__global uint *streams, uint tile_len, uint n, uint i) {
 
   // `i`th expanded parent of node is equal with:
   // `i / K`th non-expanded parent of node plus `i % K`
@@ -44,24 +50,26 @@ uint get_expanded_parent(bit_stream *stream, uint i) {
   uint offset = i & (K - 1); // i % K
 
   // Return Parent_x(node) * K + offset
-  return get_parent(stream, x) * K + offset;
+  return get_parent(streams, tile_len, n, x) * K + offset;
 }
 
-__kernel void generate_expander(__global Fr *input,
-                                __global Fr *output,
-                                replica_id id,
-                                uint window_index,
-                                uint layer_index) {
+// `n` is the index of `node` in the tile of `streams`
+Fr expander_node(__global Fr *input,
+                 __global uint *streams,
+                 uint tile_len,
+                 uint n,
+                 replica_id id,
+                 ulong window_index,
+                 uint layer_index,
+                 uint degree,
+                 uint node) {
 
-  uint node = get_global_id(0); // Nodes are processed in parallel
-  ulong node_absolute_index = (ulong)window_index * N + node;
+  ulong node_absolute_index = window_index * N + node;
 
-  bit_stream stream = gen_stream(node); // 1152 Bytes ~ 1KB
+  label_state state = label_init();
+  state = label_update(state, hash_prefix(layer_index, node_absolute_index, id));
 
-  sha256_domain state = sha256_INIT;
-  state = sha256_update(state, hash_prefix(layer_index, node_absolute_index, id));
-
-  for(uint i = 0; i < DEGREE_EXPANDER / 2; i++) {
+  for(uint i = 0; i < degree / 2; i++) {
     uint i_1 = i * 2;
     uint i_2 = i * 2 + 1;
 
@@ -69,17 +77,48 @@ __kernel void generate_expander(__global Fr *input,
     Fr x_2 = Fr_ZERO;
 
     for(uint j = 0; j < K; j++) {
-      uint parent_1 = get_expanded_parent(&stream, i_1 + j * DEGREE_EXPANDER);
-      uint parent_2 = get_expanded_parent(&stream, i_2 + j * DEGREE_EXPANDER);
+      uint parent_1 = get_expanded_parent(streams, tile_len, n, i_1 + j * degree);
+      uint parent_2 = get_expanded_parent(streams, tile_len, n, i_2 + j * degree);
 
       x_1 = Fr_add(x_1, input[parent_1]);
       x_2 = Fr_add(x_2, input[parent_2]);
     }
 
-    state = sha256_update(state, Fr_to_sha256_block(x_1, x_2));
+    state = label_update(state, Fr_to_label_block(x_1, x_2));
   }
 
-  state = sha256_finish(state, DEGREE_EXPANDER / 2 + 1);
+  return label_domain_to_Fr(label_finish(state, degree / 2 + 1));
+}
+
+// Generates the nodes of the tile of `streams`, starting from `tile_offset`
+__kernel void generate_expander(__global Fr *input,
+                                __global Fr *output,
+                                __global uint *streams,
+                                uint tile_offset,
+                                uint tile_len,
+                                replica_id id,
+                                ulong window_index,
+                                uint layer_index,
+                                uint degree) {
+
+  uint n = get_global_id(0); // Nodes of the tile are processed in parallel
+  uint node = tile_offset + n;
+  output[node] = expander_node(input, streams, tile_len, n, id, window_index, layer_index, degree, node);
+}
 
-  output[node] = sha256_domain_to_Fr(state);
+__kernel void generate_expander_batch(__global Fr *input,
+                                      __global Fr *output,
+                                      __global uint *streams,
+                                      uint tile_offset,
+                                      uint tile_len,
+                                      __global replica_id *ids,
+                                      __global ulong *window_indices,
+                                      uint layer_index,
+                                      uint degree) {
+
+  size_t i = get_global_id(0); // Tiles of all windows are processed in parallel
+  uint w = i / tile_len;
+  uint n = i % tile_len;
+  uint node = tile_offset + n;
+  output[(size_t)w * N + node] = expander_node(input + (size_t)w * N, streams, tile_len, n, ids[w], window_indices[w], layer_index, degree, node);
 }
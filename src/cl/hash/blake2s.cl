@@ -0,0 +1,96 @@
+// BLAKE2s-256 (RFC 7693), unkeyed.
+
+typedef struct {
+  uint vals[8];
+} blake2s_domain;
+
+typedef struct {
+  uint vals[16]; // Little-endian words of the message
+} blake2s_block;
+
+typedef struct {
+  blake2s_domain h;
+  blake2s_block pending; // Last absorbed block, only compressed on finish, as it is flagged
+  uint blocks; // Number of absorbed blocks, including the pending one
+} blake2s_state;
+
+__constant uint BLAKE2S_IV[8] =
+{
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+};
+
+__constant uchar BLAKE2S_SIGMA[10][16] =
+{
+    { 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15 },
+    { 14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3 },
+    { 11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4 },
+    { 7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8 },
+    { 9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13 },
+    { 2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9 },
+    { 12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11 },
+    { 13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10 },
+    { 6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5 },
+    { 10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0 }
+};
+
+// `rotate` rotates to the left, so right rotations by `n` are left rotations by `32 - n`
+#define ROTR(x, n) rotate((uint)(x), (uint)(32 - n))
+
+#define blake2s_G(v, a, b, c, d, x, y) { \
+  v[a] = v[a] + v[b] + (x); v[d] = ROTR(v[d] ^ v[a], 16); \
+  v[c] = v[c] + v[d];       v[b] = ROTR(v[b] ^ v[c], 12); \
+  v[a] = v[a] + v[b] + (y); v[d] = ROTR(v[d] ^ v[a], 8);  \
+  v[c] = v[c] + v[d];       v[b] = ROTR(v[b] ^ v[c], 7);  \
+}
+
+// * `bytes` - Number of message bytes compressed so far, including this block
+blake2s_domain blake2s_compress(blake2s_domain h, blake2s_block m, ulong bytes, bool last) {
+  uint v[16];
+  for(uint i = 0; i < 8; i++) {
+    v[i] = h.vals[i];
+    v[i + 8] = BLAKE2S_IV[i];
+  }
+  v[12] ^= (uint)bytes;
+  v[13] ^= (uint)(bytes >> 32);
+  if(last)
+    v[14] = ~v[14];
+
+  for(uint r = 0; r < 10; r++) {
+    __constant uchar *s = BLAKE2S_SIGMA[r];
+    blake2s_G(v, 0, 4, 8, 12, m.vals[s[0]], m.vals[s[1]]);
+    blake2s_G(v, 1, 5, 9, 13, m.vals[s[2]], m.vals[s[3]]);
+    blake2s_G(v, 2, 6, 10, 14, m.vals[s[4]], m.vals[s[5]]);
+    blake2s_G(v, 3, 7, 11, 15, m.vals[s[6]], m.vals[s[7]]);
+    blake2s_G(v, 0, 5, 10, 15, m.vals[s[8]], m.vals[s[9]]);
+    blake2s_G(v, 1, 6, 11, 12, m.vals[s[10]], m.vals[s[11]]);
+    blake2s_G(v, 2, 7, 8, 13, m.vals[s[12]], m.vals[s[13]]);
+    blake2s_G(v, 3, 4, 9, 14, m.vals[s[14]], m.vals[s[15]]);
+  }
+
+  for(uint i = 0; i < 8; i++)
+    h.vals[i] ^= v[i] ^ v[i + 8];
+  return h;
+}
+
+blake2s_state blake2s_init() {
+  blake2s_state state;
+  for(uint i = 0; i < 8; i++)
+    state.h.vals[i] = BLAKE2S_IV[i];
+  state.h.vals[0] ^= 0x01010020; // Digest length of 32 bytes, no key
+  state.blocks = 0;
+  return state;
+}
+
+blake2s_state blake2s_update(blake2s_state state, blake2s_block block) {
+  if(state.blocks > 0)
+    state.h = blake2s_compress(state.h, state.pending, (ulong)state.blocks * 64, false);
+  state.pending = block;
+  state.blocks++;
+  return state;
+}
+
+// At least one block should have been absorbed (Empty messages are not supported)
+blake2s_domain blake2s_finish(blake2s_state state) {
+  return blake2s_compress(state.h, state.pending, (ulong)state.blocks * 64, true);
+}
@@ -0,0 +1,29 @@
+// Labeling hash interface, implemented with BLAKE2s-256. Blocks and digests hold the
+// hashed bytes as big-endian words, while BLAKE2s takes and produces little-endian words.
+
+typedef sha256_block label_block;
+typedef blake2s_state label_state;
+typedef sha256_domain label_domain;
+
+#define label_ZERO sha256_ZERO
+#define SWAP_BYTES(x) as_uint(as_uchar4(x).s3210)
+
+label_state label_init() {
+  return blake2s_init();
+}
+
+label_state label_update(label_state state, label_block block) {
+  blake2s_block b;
+  for(uint i = 0; i < 16; i++)
+    b.vals[i] = SWAP_BYTES(block.vals[i]);
+  return blake2s_update(state, b);
+}
+
+// * `blocks` - Number of hashed blocks (Already counted by the state)
+label_domain label_finish(label_state state, uint blocks) {
+  blake2s_domain h = blake2s_finish(state);
+  label_domain d;
+  for(uint i = 0; i < 8; i++)
+    d.vals[i] = SWAP_BYTES(h.vals[i]);
+  return d;
+}
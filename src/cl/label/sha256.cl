@@ -0,0 +1,21 @@
+// Labeling hash interface, implemented with SHA-256. Blocks and digests hold the
+// hashed bytes as big-endian words.
+
+typedef sha256_block label_block;
+typedef sha256_domain label_state;
+typedef sha256_domain label_domain;
+
+#define label_ZERO sha256_ZERO
+
+label_state label_init() {
+  return sha256_INIT;
+}
+
+label_state label_update(label_state state, label_block block) {
+  return sha256_update(state, block);
+}
+
+// * `blocks` - Number of hashed blocks
+label_domain label_finish(label_state state, uint blocks) {
+  return sha256_finish(state, blocks);
+}
@@ -1,10 +1,26 @@
+Fr mask_node(replica_id id, ulong window_index, uint node_index) {
+  ulong node_absolute_index = window_index * N + node_index;
+  uint layer_index = 1; // Mask layer is always layer 1 (Or 0?)
+  label_state state = label_update(label_init(), hash_prefix(layer_index, node_absolute_index, id));
+  return label_domain_to_Fr(label_finish(state, 1));
+}
+
 __kernel void generate_mask(__global Fr *output,
                             replica_id id,
-                            uint window_index) {
+                            ulong window_index) {
 
   uint node_index = get_global_id(0); // Nodes are processed in parallel
-  ulong node_absolute_index = (ulong)window_index * N + node_index;
-  uint layer_index = 1; // Mask layer is always layer 1 (Or 0?)
-  sha256_domain state = sha256(hash_prefix(layer_index, node_absolute_index, id));
-  output[node_index] = sha256_domain_to_Fr(state);
+  output[node_index] = mask_node(id, window_index, node_index);
+}
+
+// Batched kernels are launched on `N` nodes per window, windows are stored
+// consecutively in the buffers, and window `w` has replica id `ids[w]` and
+// window index `window_indices[w]`.
+__kernel void generate_mask_batch(__global Fr *output,
+                                  __global replica_id *ids,
+                                  __global ulong *window_indices) {
+
+  size_t i = get_global_id(0);
+  uint w = i / N;
+  output[i] = mask_node(ids[w], window_indices[w], i % N);
 }
@@ -0,0 +1,235 @@
+use crate::merkle::{hash_row, tree_constants, tree_height};
+use crate::{Layer, NSEError, NSEResult, Node};
+use generic_array::typenum::{U10, U11, U12, U13, U14, U15, U16, U2, U3, U4, U5, U6, U7, U8, U9};
+use neptune::poseidon::{Poseidon, PoseidonConstants};
+use neptune::Arity;
+use paired::bls12_381::{Bls12, Fr};
+use sha2::{Digest, Sha256};
+
+// Commitments of a sector, as the NSE PoRep of storage-proofs computes them:
+//
+// * `comm_d` is the root of the binary SHA-256 tree (`BinaryMerkleTree<Sha256Hasher>`)
+//   of the original data of the sector, whose leaves are its nodes.
+// * Each layer of a window is committed by the root of its 8-ary Poseidon tree (The
+//   same root `TreeBuilder`s return). The tree of a layer over the sector has the trees
+//   of its windows as sub-trees, whose roots are hashed together according to the
+//   `SectorTreeShape` of the tree type.
+// * `comm_r` is the Poseidon hash of the commitments of all key layers (Except the last
+//   one, which is only combined into the replica), followed by the replica commitment.
+
+/// Arities of the levels of the sector trees of layers above the window trees, which are
+/// the `SubTreeArity` and `TopTreeArity` of the storage-proofs tree type (0 if a level
+/// is absent). The number of windows of the sector should be the product of the arities
+/// of the present levels.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct SectorTreeShape {
+    pub sub_tree_arity: usize,
+    pub top_tree_arity: usize,
+}
+
+impl SectorTreeShape {
+    fn num_windows(&self) -> usize {
+        match (self.sub_tree_arity, self.top_tree_arity) {
+            (0, _) => 1,
+            (sub, 0) => sub,
+            (sub, top) => sub * top,
+        }
+    }
+}
+
+fn poseidon<A: Arity<Fr>>(preimage: &[Node]) -> Node {
+    let constants = PoseidonConstants::<Bls12, A>::new();
+    Node(Poseidon::new_with_preimage(Node::as_frs(preimage), &constants).hash())
+}
+
+// Poseidon hash of `preimage`, with the constants of its arity
+fn hash_nodes(preimage: &[Node]) -> NSEResult<Node> {
+    Ok(match preimage.len() {
+        2 => poseidon::<U2>(preimage),
+        3 => poseidon::<U3>(preimage),
+        4 => poseidon::<U4>(preimage),
+        5 => poseidon::<U5>(preimage),
+        6 => poseidon::<U6>(preimage),
+        7 => poseidon::<U7>(preimage),
+        8 => poseidon::<U8>(preimage),
+        9 => poseidon::<U9>(preimage),
+        10 => poseidon::<U10>(preimage),
+        11 => poseidon::<U11>(preimage),
+        12 => poseidon::<U12>(preimage),
+        13 => poseidon::<U13>(preimage),
+        14 => poseidon::<U14>(preimage),
+        15 => poseidon::<U15>(preimage),
+        16 => poseidon::<U16>(preimage),
+        len => {
+            return Err(NSEError::InvalidCommitmentInput(format!(
+                "Cannot hash {} nodes at once!",
+                len
+            )))
+        }
+    })
+}
+
+// SHA-256 of two nodes, truncated to a field element (Like `Sha256Hasher` nodes)
+fn sha256_node(left: &Node, right: &Node) -> NSEResult<Node> {
+    let hash = Sha256::new()
+        .chain(&left.to_bytes()[..])
+        .chain(&right.to_bytes()[..])
+        .result();
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&hash[..]);
+    bytes[31] &= 0b0011_1111;
+    Ok(Node::from_bytes(&bytes)?)
+}
+
+/// Root of the tree of a single window layer.
+pub fn window_root(layer: &Layer) -> NSEResult<Node> {
+    let constants = tree_constants();
+    let mut row = layer.0.clone();
+    for _ in 0..tree_height(row.len())? {
+        row = hash_row(&constants, &row);
+    }
+    Ok(row[0])
+}
+
+/// Combines the window roots of a layer (In window order) into its commitment, which
+/// is the root of the sector tree of the layer.
+pub fn layer_commitment(window_roots: &[Node], shape: SectorTreeShape) -> NSEResult<Node> {
+    if window_roots.len() != shape.num_windows() {
+        return Err(NSEError::InvalidCommitmentInput(format!(
+            "Expected {} windows, got {}!",
+            shape.num_windows(),
+            window_roots.len()
+        )));
+    }
+    if shape.sub_tree_arity == 0 {
+        return Ok(window_roots[0]);
+    }
+    let sub_roots = window_roots
+        .chunks(shape.sub_tree_arity)
+        .map(hash_nodes)
+        .collect::<NSEResult<Vec<_>>>()?;
+    if shape.top_tree_arity == 0 {
+        Ok(sub_roots[0])
+    } else {
+        hash_nodes(&sub_roots)
+    }
+}
+
+/// Data commitment of a sector, given its original data split into windows (Whose total
+/// number of nodes should be a power of 2).
+pub fn comm_d(original_data: &[Layer]) -> NSEResult<Node> {
+    let mut row = original_data
+        .iter()
+        .flat_map(|window| window.0.iter().cloned())
+        .collect::<Vec<_>>();
+    if row.len().count_ones() != 1 {
+        return Err(NSEError::InvalidCommitmentInput(format!(
+            "Number of nodes ({}) should be a power of 2!",
+            row.len()
+        )));
+    }
+    while row.len() > 1 {
+        row = row
+            .chunks(2)
+            .map(|pair| sha256_node(&pair[0], &pair[1]))
+            .collect::<NSEResult<Vec<_>>>()?;
+    }
+    Ok(row[0])
+}
+
+/// Replica commitment of a sector, given the roots of every layer of every window,
+/// as returned by `Sealer` (`window_roots[window][layer]`, ending with the replica layer).
+pub fn comm_r(window_roots: &[Vec<Node>], shape: SectorTreeShape) -> NSEResult<Node> {
+    let num_layers = window_roots.first().map(|r| r.len()).unwrap_or(0);
+    if num_layers < 2 || window_roots.iter().any(|r| r.len() != num_layers) {
+        return Err(NSEError::InvalidCommitmentInput(
+            "Every window should have the same number of layer roots, and at least two!".into(),
+        ));
+    }
+
+    // Key layer commitments, followed by the replica commitment
+    let layer_commitments = (0..num_layers)
+        .map(|l| {
+            let roots = window_roots.iter().map(|r| r[l]).collect::<Vec<_>>();
+            layer_commitment(&roots, shape)
+        })
+        .collect::<NSEResult<Vec<_>>>()?;
+    hash_nodes(&layer_commitments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::{build_tree, new_tree_builder};
+    use neptune::batch_hasher::BatcherType;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_window_root() {
+        let mut rng = thread_rng();
+        let layer = Layer::random(&mut rng, 512);
+        let mut tree_builder = new_tree_builder(BatcherType::CPU, 512, 2).unwrap();
+        let tree = build_tree(&mut tree_builder, &layer.0).unwrap();
+        assert_eq!(window_root(&layer).unwrap(), tree[tree.len() - 1]);
+    }
+
+    #[test]
+    fn test_commitments() {
+        let mut rng = thread_rng();
+        let windows = (0..4)
+            .map(|_| Layer::random(&mut rng, 64))
+            .collect::<Vec<_>>();
+        let nodes = windows
+            .iter()
+            .flat_map(|w| w.0.iter().cloned())
+            .collect::<Vec<_>>();
+        let mut row = nodes.clone();
+        while row.len() > 1 {
+            row = row
+                .chunks(2)
+                .map(|pair| sha256_node(&pair[0], &pair[1]).unwrap())
+                .collect();
+        }
+        assert_eq!(comm_d(&windows).unwrap(), row[0]);
+        assert!(comm_d(&windows[..3]).is_err());
+
+        let shape = SectorTreeShape {
+            sub_tree_arity: 2,
+            top_tree_arity: 2,
+        };
+        let window_roots = (0..4)
+            .map(|_| (0..3).map(|_| Node::random(&mut rng)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let commitments = (0..3)
+            .map(|l| {
+                let roots = window_roots.iter().map(|r| r[l]).collect::<Vec<_>>();
+                let left = poseidon::<U2>(&roots[..2]);
+                let right = poseidon::<U2>(&roots[2..]);
+                assert_eq!(
+                    layer_commitment(&roots, shape).unwrap(),
+                    poseidon::<U2>(&[left, right])
+                );
+                layer_commitment(&roots, shape).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            comm_r(&window_roots, shape).unwrap(),
+            poseidon::<U3>(&commitments)
+        );
+
+        let single = SectorTreeShape {
+            sub_tree_arity: 4,
+            top_tree_arity: 0,
+        };
+        let roots = window_roots.iter().map(|r| r[0]).collect::<Vec<_>>();
+        assert_eq!(
+            layer_commitment(&roots, single).unwrap(),
+            poseidon::<U4>(&roots)
+        );
+        assert!(layer_commitment(&roots[..2], single).is_err());
+
+        let mut bad_roots = window_roots.clone();
+        bad_roots[1].pop();
+        assert!(comm_r(&bad_roots, shape).is_err());
+    }
+}
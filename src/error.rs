@@ -21,6 +21,50 @@ pub enum NSEError {
     GPU(#[from] GPUError),
     #[error("Neptune Error: {0}")]
     Neptune(#[from] neptune::error::Error),
+    #[error("IO Error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Tree building is disabled!")]
+    TreeBuildingDisabled,
+    #[error("Tree worker died!")]
+    TreeWorkerDied,
+    #[error("Invalid tree: {0}")]
+    InvalidTree(String),
+    #[error("Node index {0} is out of range!")]
+    NodeIndexOutOfRange(usize),
+    #[error("Invalid commitment input: {0}")]
+    InvalidCommitmentInput(String),
+    #[error("Window index {0} is too large!")]
+    InvalidWindowIndex(u64),
+    #[error("Segment of {len} nodes at offset {offset} exceeds the window!")]
+    SegmentOutOfRange { offset: usize, len: usize },
+    #[error("Invalid node: {0}")]
+    InvalidNode(#[from] ff::PrimeFieldDecodingError),
+    #[error("Invalid padding: {0}")]
+    InvalidPadding(String),
+    #[error("Data length is not a multiple of node size!")]
+    PartialNode,
+    #[error("Invalid update: {0}")]
+    InvalidUpdate(String),
+    #[error("Invalid batch: {0}")]
+    InvalidBatch(String),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Sealer pools should have at least one lane per device!")]
+    InvalidLaneCount,
+    #[error("Combine batch size should be positive!")]
+    InvalidCombineBatchSize,
+    #[error("Invalid stream tile size: {0}")]
+    InvalidStreamTileSize(String),
+    #[error("Invalid tuning file: {0}")]
+    InvalidTuningFile(String),
+    #[error("Expected an output of {expected} nodes, got {actual}!")]
+    OutputLengthMismatch { expected: usize, actual: usize },
+    #[error("Expected a layer of {expected} nodes, got {actual}!")]
+    LayerLengthMismatch { expected: usize, actual: usize },
+    #[error("Invalid layer index: {0:?}!")]
+    InvalidLayerIndex(crate::LayerIndex),
+    #[error("Layer {0} of the layer schedule is not of the requested kind!")]
+    LayerKindMismatch(usize),
 }
 
 pub type NSEResult<T> = std::result::Result<T, NSEError>;
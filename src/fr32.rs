@@ -0,0 +1,148 @@
+//! Fr32 padding of raw bytes into nodes.
+//!
+//! Raw bytes are treated as a little-endian bit stream, and every node carries
+//! `FR32_PAYLOAD_BITS` bits of it, leaving its two most significant bits zero,
+//! so that every node is a canonical field element (I.e. 127 bytes are padded
+//! into 4 nodes).
+
+use crate::{Layer, NSEError, NSEResult, Node, NODE_SIZE};
+
+/// Number of payload bits per node.
+pub const FR32_PAYLOAD_BITS: usize = 254;
+
+// Mask of the payload bits of the last byte of a node
+const LAST_BYTE_MASK: u8 = 0b0011_1111;
+
+/// Number of nodes needed for `unpadded_len` raw bytes.
+pub fn padded_node_count(unpadded_len: usize) -> usize {
+    (unpadded_len * 8 + FR32_PAYLOAD_BITS - 1) / FR32_PAYLOAD_BITS
+}
+
+/// Maximum number of raw bytes which fit in `node_count` nodes.
+pub fn unpadded_len(node_count: usize) -> usize {
+    node_count * FR32_PAYLOAD_BITS / 8
+}
+
+/// Pads raw bytes into nodes.
+pub fn pad(data: &[u8]) -> Vec<Node> {
+    let get = |i: usize| data.get(i).cloned().unwrap_or(0);
+    (0..padded_node_count(data.len()))
+        .map(|i| {
+            let offset = i * FR32_PAYLOAD_BITS;
+            let (start, shift) = (offset / 8, offset % 8);
+            let mut bytes = [0u8; NODE_SIZE];
+            for (j, b) in bytes.iter_mut().enumerate() {
+                *b = get(start + j) >> shift;
+                if shift > 0 {
+                    *b |= get(start + j + 1) << (8 - shift);
+                }
+            }
+            bytes[NODE_SIZE - 1] &= LAST_BYTE_MASK;
+            Node::from_bytes(&bytes).expect("Padded nodes are always canonical!")
+        })
+        .collect()
+}
+
+/// Extracts the first `unpadded_len` raw bytes out of padded nodes. Fails if the nodes
+/// cannot hold that many bytes, or if any of them has one of its two most significant
+/// bits set (I.e. it was not produced by `pad`).
+pub fn unpad(nodes: &[Node], unpadded_len: usize) -> NSEResult<Vec<u8>> {
+    if unpadded_len > self::unpadded_len(nodes.len()) {
+        return Err(NSEError::InvalidPadding(format!(
+            "{} nodes cannot hold {} bytes!",
+            nodes.len(),
+            unpadded_len
+        )));
+    }
+    let mut data = vec![0u8; (nodes.len() * FR32_PAYLOAD_BITS + 7) / 8 + 1];
+    for (i, node) in nodes.iter().enumerate() {
+        let offset = i * FR32_PAYLOAD_BITS;
+        let (start, shift) = (offset / 8, offset % 8);
+        let bytes = node.to_bytes();
+        if bytes[NODE_SIZE - 1] & !LAST_BYTE_MASK != 0 {
+            return Err(NSEError::InvalidPadding(format!(
+                "Node {} is not fr32 padded!",
+                i
+            )));
+        }
+        for (j, &b) in bytes.iter().enumerate() {
+            data[start + j] |= b << shift;
+            if shift > 0 {
+                data[start + j + 1] |= b >> (8 - shift);
+            }
+        }
+    }
+    data.truncate(unpadded_len);
+    Ok(data)
+}
+
+/// Pads raw bytes into window layers, the last window is zero-padded to `num_nodes_window` nodes.
+pub fn pad_to_layers(data: &[u8], num_nodes_window: usize) -> Vec<Layer> {
+    let nodes = pad(data);
+    nodes
+        .chunks(num_nodes_window)
+        .map(|chunk| {
+            let mut layer = chunk.to_vec();
+            layer.resize(num_nodes_window, Node::default());
+            Layer(layer)
+        })
+        .collect()
+}
+
+/// Extracts the first `unpadded_len` raw bytes out of padded window layers.
+pub fn unpad_layers(layers: &[Layer], unpadded_len: usize) -> NSEResult<Vec<u8>> {
+    let nodes = layers
+        .iter()
+        .flat_map(|l| l.0.iter().cloned())
+        .collect::<Vec<_>>();
+    unpad(&nodes, unpadded_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn test_fr32_layout() {
+        assert_eq!(padded_node_count(127), 4);
+        assert_eq!(padded_node_count(128), 5);
+        assert_eq!(unpadded_len(4), 127);
+
+        let nodes = pad(&[0xffu8; 32]);
+        assert_eq!(nodes.len(), 2);
+        let mut first = [0xffu8; NODE_SIZE];
+        first[NODE_SIZE - 1] = LAST_BYTE_MASK;
+        assert_eq!(nodes[0].to_bytes(), first);
+        let mut second = [0u8; NODE_SIZE];
+        second[0] = 0b11;
+        assert_eq!(nodes[1].to_bytes(), second);
+    }
+
+    #[test]
+    fn test_fr32_roundtrip() {
+        let mut rng = thread_rng();
+        for &len in [0, 1, 31, 32, 127, 128, 1000, 4065].iter() {
+            let data = (0..len).map(|_| rng.gen()).collect::<Vec<u8>>();
+            let nodes = pad(&data);
+            assert_eq!(nodes.len(), padded_node_count(len));
+            assert_eq!(unpad(&nodes, len).unwrap(), data);
+            assert!(unpad(&nodes, unpadded_len(nodes.len()) + 1).is_err());
+
+            let layers = pad_to_layers(&data, 16);
+            assert_eq!(layers.len(), (nodes.len() + 15) / 16);
+            assert!(layers.iter().all(|l| l.0.len() == 16));
+            assert_eq!(unpad_layers(&layers, len).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_fr32_unpad_malformed() {
+        let mut rng = thread_rng();
+        let mut bytes = [0u8; NODE_SIZE];
+        bytes[NODE_SIZE - 1] = 0b0100_0000;
+        let mut nodes = pad(&(0..64).map(|_| rng.gen()).collect::<Vec<u8>>());
+        nodes[1] = Node::from_bytes(&bytes).unwrap();
+        assert!(unpad(&nodes, 32).is_err());
+    }
+}
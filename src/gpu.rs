@@ -1,14 +1,15 @@
 use super::{
-    sources, utils, Config, GPUError, GPUResult, Layer, NSEResult, NarrowStackedExpander, Node,
-    ReplicaId, COMBINE_BATCH_SIZE,
+    sources, utils, Config, GPUError, GPUResult, KeySpec, Layer, LayerSpec, NSEError, NSEResult,
+    NarrowStackedExpander, Node, OrdinaryNode, ReplicaId, COMBINE_BATCH_SIZE,
 };
-use generic_array::typenum::U8;
-use log::info;
-use neptune::batch_hasher::BatcherType;
-use neptune::cl::GPUSelector;
-use neptune::tree_builder::TreeBuilder;
+use crate::cache::{KeyCache, KeyId};
+use crate::profile::{ProfileReport, Profiler};
+use crate::tree::TreeBackend;
+use crate::tune::LocalWorkSizes;
+use log::{info, warn};
 use ocl::builders::KernelBuilder;
-use ocl::{Buffer, Device, OclPrm, ProQue};
+use ocl::flags::{CommandQueueProperties, MemFlags};
+use ocl::{Buffer, Context, Device, Event, OclPrm, ProQue, Program, Queue};
 
 pub fn is_little_endian(d: ocl::Device) -> GPUResult<bool> {
     match d.info(ocl::enums::DeviceInfo::EndianLittle)? {
@@ -17,36 +18,93 @@ pub fn is_little_endian(d: ocl::Device) -> GPUResult<bool> {
     }
 }
 
-fn write_buffer<T: OclPrm>(buff: &mut Buffer<T>, offset: usize, segment: &[T]) -> GPUResult<()> {
-    info!("Pushing data...");
-    buff.create_sub_buffer(None, offset, segment.len())?
-        .write(segment)
-        .enq()?;
-    Ok(())
-}
-
-fn read_buffer<T: OclPrm>(buff: &Buffer<T>, offset: usize, segment: &mut [T]) -> GPUResult<()> {
-    info!("Pulling results...");
-    buff.create_sub_buffer(None, offset, segment.len())?
-        .read(segment)
-        .enq()?;
+fn check_output_len(expected: usize, output: &[Node]) -> NSEResult<()> {
+    if output.len() != expected {
+        return Err(NSEError::OutputLengthMismatch {
+            expected,
+            actual: output.len(),
+        });
+    }
     Ok(())
 }
 
 // Make `Node` movable to GPU buffers by implementing `OclPrm`
 unsafe impl OclPrm for Node {}
+unsafe impl OclPrm for OrdinaryNode {}
 unsafe impl OclPrm for ReplicaId {}
 
 #[derive(Debug, Clone, Copy)]
 pub enum TreeOptions {
-    Enabled { rows_to_discard: usize },
+    /// Build trees on the same GPU used for generating layers.
+    Enabled {
+        rows_to_discard: usize,
+    },
+    /// Build trees on CPU, through neptune's CPU batcher. Trees can also be rebuilt on CPU
+    /// without any `GPUContext`, see `TreeRebuilder::new_cpu`.
+    EnabledCPU {
+        rows_to_discard: usize,
+    },
+    /// Build trees on a different GPU, so that layers can be generated while
+    /// trees of previous layers are being built. Devices are assigned explicitly:
+    /// Spreading trees over a pool of devices is deliberately out of scope.
+    EnabledOnDevice {
+        rows_to_discard: usize,
+        device: Device,
+    },
     Disabled,
 }
 
+impl TreeOptions {
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            TreeOptions::Disabled => false,
+            _ => true,
+        }
+    }
+}
+
+/// NSE kernels compiled for a device, which can be shared by several `GPUContext`s
+/// (Possibly on different threads), each running on its own command queue.
+#[derive(Clone)]
+pub struct SharedProgram {
+    context: Context,
+    program: Program,
+    device: Device,
+    config: Config,
+}
+
+impl SharedProgram {
+    pub fn new(device: Device, config: Config) -> NSEResult<SharedProgram> {
+        if !is_little_endian(device)? {
+            Err(GPUError::Other("Device should be little-endian!".into()))?;
+        }
+
+        info!("Compiling kernels...");
+        let code = sources::generate_nse_program(&config)?;
+        let pro_que = ProQue::builder()
+            .device(device)
+            .src(code)
+            .dims(config.num_nodes_window)
+            .build()?;
+
+        Ok(SharedProgram {
+            context: pro_que.context().clone(),
+            program: pro_que.program().clone(),
+            device,
+            config,
+        })
+    }
+}
+
 // Manages buffers
 pub struct GPUContext {
     pro_que: ProQue,
-    tree_builder: Option<TreeBuilder<U8>>,
+    trees: Option<TreeBackend>,
+    tree_options: TreeOptions,
+    shared: SharedProgram,
+    local_work_sizes: LocalWorkSizes,
+    profiler: Option<Profiler>,
+    pinned: Option<Buffer<Node>>, // Pinned host buffer, for staging transfers
     config: Config,
 }
 
@@ -60,40 +118,310 @@ impl GPUContext {
             "Initializing a new NSE GPU context on device: {}",
             device.name()?
         );
+        GPUContext::from_shared(&SharedProgram::new(device, config)?, tree_options)
+    }
 
-        if !is_little_endian(device)? {
-            Err(GPUError::Other("Device should be little-endian!".into()))?;
-        }
+    /// Same as `new`, but operations are run on a queue with profiling enabled, recording
+    /// the device time of every kernel call and buffer transfer (See `GPU::profile_report`).
+    /// Profiling cannot be enabled later, as buffers are bound to the queue they are
+    /// created with.
+    pub fn new_profiled(
+        device: Device,
+        config: Config,
+        tree_options: TreeOptions,
+    ) -> NSEResult<GPUContext> {
+        GPUContext::from_shared_profiled(&SharedProgram::new(device, config)?, tree_options)
+    }
 
-        info!("Compiling kernels...");
-        let code = sources::generate_nse_program(config);
-        let pro_que = ProQue::builder()
-            .device(device)
-            .src(code)
-            .dims(config.num_nodes_window)
-            .build()?;
+    /// Creates a context running the already compiled kernels of `shared` on a new
+    /// in-order command queue, so that it can work concurrently with other contexts
+    /// of the same device.
+    pub fn from_shared(shared: &SharedProgram, tree_options: TreeOptions) -> NSEResult<GPUContext> {
+        let trees = TreeBackend::new(tree_options, shared.device, shared.config.num_nodes_window)?;
+        GPUContext::with_queue(shared, tree_options, trees, false)
+    }
+
+    // Same as `from_shared`, building trees through an existing (Shared) tree backend
+    pub(crate) fn from_shared_with_trees(
+        shared: &SharedProgram,
+        tree_options: TreeOptions,
+        trees: Option<TreeBackend>,
+    ) -> NSEResult<GPUContext> {
+        GPUContext::with_queue(shared, tree_options, trees, false)
+    }
+
+    /// Same as `from_shared`, with profiling enabled (See `new_profiled`).
+    pub fn from_shared_profiled(
+        shared: &SharedProgram,
+        tree_options: TreeOptions,
+    ) -> NSEResult<GPUContext> {
+        let trees = TreeBackend::new(tree_options, shared.device, shared.config.num_nodes_window)?;
+        GPUContext::with_queue(shared, tree_options, trees, true)
+    }
+
+    fn with_queue(
+        shared: &SharedProgram,
+        tree_options: TreeOptions,
+        trees: Option<TreeBackend>,
+        profiling: bool,
+    ) -> NSEResult<GPUContext> {
+        let config = shared.config.clone();
+        let properties = if profiling {
+            Some(CommandQueueProperties::new().profiling())
+        } else {
+            None
+        };
+        let queue = Queue::new(&shared.context, shared.device, properties)?;
+        let pro_que = ProQue::new(
+            shared.context.clone(),
+            queue,
+            shared.program.clone(),
+            Some(config.num_nodes_window),
+        );
 
         Ok(GPUContext {
             pro_que,
             config,
-            tree_builder: match tree_options {
-                TreeOptions::Enabled { rows_to_discard } => Some(TreeBuilder::<U8>::new(
-                    Some(BatcherType::CustomGPU(GPUSelector::BusId(
-                        utils::get_bus_id(device)?,
-                    ))),
-                    config.num_nodes_window,
-                    TREE_BUILDER_BATCH_SIZE,
-                    rows_to_discard,
-                )?),
-                TreeOptions::Disabled => None,
+            trees,
+            tree_options,
+            shared: shared.clone(),
+            local_work_sizes: LocalWorkSizes::new(),
+            profiler: if profiling {
+                Some(Profiler::default())
+            } else {
+                None
             },
+            pinned: None,
         })
     }
 
+    pub fn shared_program(&self) -> &SharedProgram {
+        &self.shared
+    }
+
+    /// Creates a context sharing the kernels of this one, on a separate command queue
+    /// (Profiled if this one is). Trees built on a worker thread are shared too.
+    pub fn fork(&self) -> NSEResult<GPUContext> {
+        let trees = match self.trees.as_ref().and_then(|t| t.share()) {
+            Some(trees) => Some(trees),
+            None => TreeBackend::new(
+                self.tree_options,
+                self.shared.device,
+                self.config.num_nodes_window,
+            )?,
+        };
+        let mut ctx =
+            GPUContext::with_queue(&self.shared, self.tree_options, trees, self.is_profiling())?;
+        ctx.local_work_sizes = self.local_work_sizes.clone();
+        Ok(ctx)
+    }
+
+    pub fn device(&self) -> Device {
+        self.shared.device
+    }
+
+    pub fn is_profiling(&self) -> bool {
+        self.profiler.is_some()
+    }
+
+    // Record the event of an operation, if profiling is enabled
+    pub(crate) fn record(&mut self, name: &str, event: Event) -> NSEResult<()> {
+        match self.profiler.as_mut() {
+            Some(profiler) => profiler.record(name, event),
+            None => Ok(()),
+        }
+    }
+
+    /// Transfer nodes between host and device through a pinned host buffer (Allocated
+    /// by the driver), instead of letting the driver stage them through pageable memory.
+    /// The pinned buffer holds a layer, and is reused across transfers: It is mapped and
+    /// accessed by the host in place, and copied from (Or to) the target buffer on device.
+    pub fn set_pinned_transfers(&mut self, enabled: bool) -> NSEResult<()> {
+        self.pinned = if enabled {
+            let leaf_count = self.leaf_count();
+            Some(
+                self.pro_que
+                    .buffer_builder::<Node>()
+                    .flags(MemFlags::new().read_write().alloc_host_ptr())
+                    .len(leaf_count)
+                    .build()?,
+            )
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    pub fn has_pinned_transfers(&self) -> bool {
+        self.pinned.is_some()
+    }
+
+    fn write_direct<T: OclPrm>(
+        &mut self,
+        buff: &mut Buffer<T>,
+        offset: usize,
+        segment: &[T],
+    ) -> NSEResult<()> {
+        let mut event = Event::empty();
+        buff.create_sub_buffer(None, offset, segment.len())?
+            .write(segment)
+            .enew(&mut event)
+            .enq()?;
+        self.record("write_buffer", event)
+    }
+
+    fn read_direct<T: OclPrm>(
+        &mut self,
+        buff: &Buffer<T>,
+        offset: usize,
+        segment: &mut [T],
+    ) -> NSEResult<()> {
+        let mut event = Event::empty();
+        buff.create_sub_buffer(None, offset, segment.len())?
+            .read(segment)
+            .enew(&mut event)
+            .enq()?;
+        self.record("read_buffer", event)
+    }
+
+    pub(crate) fn write_buffer(
+        &mut self,
+        buff: &mut Buffer<Node>,
+        offset: usize,
+        segment: &[Node],
+    ) -> NSEResult<()> {
+        if self.pinned.is_none() {
+            info!("Pushing data...");
+            return self.write_direct(buff, offset, segment);
+        }
+        self.write_buffer_with(buff, offset, segment.len(), |start, chunk| {
+            chunk.copy_from_slice(&segment[start..start + chunk.len()]);
+            Ok(())
+        })
+    }
+
+    // Write `len` nodes into `buff` from `offset`, filled by `fill`, which is called with
+    // successive chunks of the range along with their offset in the range. With pinned
+    // transfers, chunks are the mapped pinned buffer itself, so that they are filled in
+    // place. Otherwise the range is filled in a temporary host buffer.
+    pub(crate) fn write_buffer_with<F>(
+        &mut self,
+        buff: &mut Buffer<Node>,
+        offset: usize,
+        len: usize,
+        mut fill: F,
+    ) -> NSEResult<()>
+    where
+        F: FnMut(usize, &mut [Node]) -> NSEResult<()>,
+    {
+        info!("Pushing data...");
+        let pinned = match self.pinned.take() {
+            Some(pinned) => pinned,
+            None => {
+                let mut segment = vec![Node::default(); len];
+                fill(0, &mut segment)?;
+                return self.write_direct(buff, offset, &segment);
+            }
+        };
+        let result = (|| -> NSEResult<()> {
+            let mut start = 0;
+            while start < len {
+                let chunk_len = std::cmp::min(pinned.len(), len - start);
+                let mut mapped = unsafe { pinned.map().write_invalidate().len(chunk_len).enq()? };
+                fill(start, &mut mapped)?;
+                mapped.unmap().enq()?;
+                let from = pinned.create_sub_buffer(None, 0, chunk_len)?;
+                let to = buff.create_sub_buffer(None, offset + start, chunk_len)?;
+                self.copy_buffer(&from, &to)?;
+                start += chunk_len;
+            }
+            Ok(())
+        })();
+        self.pinned = Some(pinned);
+        result
+    }
+
+    pub(crate) fn read_buffer(
+        &mut self,
+        buff: &Buffer<Node>,
+        offset: usize,
+        segment: &mut [Node],
+    ) -> NSEResult<()> {
+        info!("Pulling results...");
+        let pinned = match self.pinned.take() {
+            Some(pinned) => pinned,
+            None => return self.read_direct(buff, offset, segment),
+        };
+        let result = (|| -> NSEResult<()> {
+            for (i, chunk) in segment.chunks_mut(pinned.len()).enumerate() {
+                let from = buff.create_sub_buffer(None, offset + i * pinned.len(), chunk.len())?;
+                self.copy_buffer(&from, &pinned)?;
+                // Mapping waits for the copy, as the queue is in-order
+                let mut mapped = unsafe { pinned.map().read().len(chunk.len()).enq()? };
+                chunk.copy_from_slice(&mapped);
+                mapped.unmap().enq()?;
+            }
+            Ok(())
+        })();
+        self.pinned = Some(pinned);
+        result
+    }
+
+    pub(crate) fn copy_buffer<T: OclPrm>(
+        &mut self,
+        from: &Buffer<T>,
+        to: &Buffer<T>,
+    ) -> NSEResult<()> {
+        let mut event = Event::empty();
+        from.copy(to, None, None).enew(&mut event).enq()?;
+        self.record("copy_buffer", event)
+    }
+
+    /// Set the local work size of a kernel, `None` leaves it to the driver.
+    pub fn set_local_work_size(&mut self, kernel_name: &str, local_work_size: Option<usize>) {
+        match local_work_size {
+            Some(size) => {
+                assert!(size > 0);
+                self.local_work_sizes.insert(kernel_name.to_string(), size);
+            }
+            None => {
+                self.local_work_sizes.remove(kernel_name);
+            }
+        }
+    }
+
+    pub fn local_work_sizes(&self) -> &LocalWorkSizes {
+        &self.local_work_sizes
+    }
+
     pub(crate) fn build_kernel(&mut self, kernel_name: &str) -> KernelBuilder {
+        let leaf_count = self.leaf_count();
+        self.build_kernel_range(kernel_name, 0, leaf_count)
+    }
+
+    // Build a kernel which is only run on nodes `[offset, offset + len)`
+    pub(crate) fn build_kernel_range(
+        &mut self,
+        kernel_name: &str,
+        offset: usize,
+        len: usize,
+    ) -> KernelBuilder {
         info!("Calling {}()...", kernel_name);
         let mut k = self.pro_que.kernel_builder(kernel_name);
-        k.global_work_size([self.leaf_count()]);
+        k.global_work_offset([offset]).global_work_size([len]);
+        // The global work size should be a multiple of the local work size, otherwise
+        // (E.g. for segments of arbitrary length) the driver chooses the local work size.
+        if let Some(&size) = self.local_work_sizes.get(kernel_name) {
+            if len % size == 0 {
+                k.local_work_size([size]);
+            } else {
+                warn!(
+                    "Local work size {} of {}() does not divide its global work size {}, \
+                     leaving it to the driver.",
+                    size, kernel_name, len
+                );
+            }
+        }
         k
     }
 
@@ -102,176 +430,768 @@ impl GPUContext {
         Ok(self.pro_que.create_buffer::<Node>()?)
     }
 
+    pub(crate) fn create_buffer_with_len(&mut self, len: usize) -> GPUResult<Buffer<Node>> {
+        info!("Creating buffer...");
+        Ok(self.pro_que.buffer_builder::<Node>().len(len).build()?)
+    }
+
+    pub(crate) fn create_typed_buffer<T: OclPrm>(&mut self, len: usize) -> GPUResult<Buffer<T>> {
+        info!("Creating buffer...");
+        Ok(self.pro_que.buffer_builder::<T>().len(len).build()?)
+    }
+
+    pub(crate) fn create_buffer_from_slice<T: OclPrm>(
+        &mut self,
+        data: &[T],
+    ) -> GPUResult<Buffer<T>> {
+        info!("Creating buffer...");
+        Ok(self
+            .pro_que
+            .buffer_builder::<T>()
+            .len(data.len())
+            .copy_host_slice(data)
+            .build()?)
+    }
+
     pub(crate) fn leaf_count(&self) -> usize {
         self.config.num_nodes_window
     }
 }
 
 macro_rules! call_kernel {
+    ($ctx:expr, $name:expr, [$offset:expr, $len:expr], $($arg:expr),*) => {{
+        let kernel =
+            $ctx
+            .build_kernel_range($name, $offset, $len)
+            $(.arg($arg))*
+            .build()?;
+        let mut event = ocl::Event::empty();
+        unsafe {
+            kernel.cmd().enew(&mut event).enq()?;
+        }
+        $ctx.record($name, event)?;
+    }};
     ($ctx:expr, $name:expr, $($arg:expr),*) => {{
         let kernel =
             $ctx
             .build_kernel($name)
             $(.arg($arg))*
             .build()?;
+        let mut event = ocl::Event::empty();
         unsafe {
-            kernel.enq()?;
+            kernel.cmd().enew(&mut event).enq()?;
         }
+        $ctx.record($name, event)?;
     }};
 }
 
-const TREE_BUILDER_BATCH_SIZE: usize = 400_000;
+// Windows whose layers are generated together, by the batched kernels. Layers are kept
+// in both forms for the same reasons as the current layer of `GPU`.
+struct WindowBatch {
+    ids: Buffer<ReplicaId>,
+    window_indices: Buffer<u64>,
+    current_layers: Buffer<OrdinaryNode>, // Last generated layers of all windows, consecutively
+    montgomery_layers: Buffer<Node>,      // Same layers, in Montgomery form
+    len: usize,
+}
+
+// Layers generated by the batched kernels
+#[derive(Clone, Copy)]
+enum BatchLayer {
+    Mask,
+    Expander(usize),
+    Butterfly(usize),
+}
+
+// Maximum size (In bytes) of the bit-streams of expander parents held on device, unless
+// set otherwise with `GPU::set_stream_tile_size`
+const STREAMS_MAX_SIZE: usize = 128 * 1024 * 1024;
+
+// Bit-streams of expander parents, of a tile of `tile_size` nodes of the window. They only
+// depend on the node index, so they are shared by all expander layers and windows, and
+// generated once if the tile is the whole window. Otherwise layers are generated a tile
+// at a time, generating the streams of every tile again, as streams take
+// `STREAM_HASH_COUNT * 32` bytes per node (E.g. 768 bytes with 16-bit parents and expander
+// degree 384, which is 384MB for a window of 2^19 nodes).
+struct ExpanderStreams {
+    tile_size: usize,
+    tile: Option<(usize, Buffer<u32>)>, // Offset of the held tile, along with its streams
+}
+
+impl ExpanderStreams {
+    // Tiles are the largest power of 2 number of nodes (At most a window) whose streams
+    // fit in `STREAMS_MAX_SIZE` bytes
+    fn new(config: &Config) -> Self {
+        let node_size = sources::stream_hash_count(config) * 32;
+        let mut tile_size = config.num_nodes_window;
+        while tile_size > 1 && tile_size * node_size > STREAMS_MAX_SIZE {
+            tile_size /= 2;
+        }
+        ExpanderStreams {
+            tile_size,
+            tile: None,
+        }
+    }
+
+    // Streams of the tile starting from node `tile_offset`, generated if not held already
+    fn tile(
+        &mut self,
+        context: &mut GPUContext,
+        config: &Config,
+        tile_offset: usize,
+    ) -> NSEResult<&Buffer<u32>> {
+        if self.tile.as_ref().map(|(offset, _)| *offset) != Some(tile_offset) {
+            let streams = match self.tile.take() {
+                Some((_, streams)) => streams,
+                None => {
+                    let len = self.tile_size * sources::stream_hash_count(config) * 8; // 8 `uint`s per hash
+                    context.create_typed_buffer::<u32>(len)?
+                }
+            };
+            call_kernel!(
+                context,
+                "generate_streams",
+                [0, self.tile_size],
+                &streams,
+                tile_offset as u32
+            );
+            self.tile = Some((tile_offset, streams));
+        }
+        Ok(&self.tile.as_ref().unwrap().1)
+    }
+}
 
 pub struct GPU {
     context: GPUContext,
+    batch: Option<WindowBatch>,
     combine_batch_size: usize,
-    current_layer: Buffer<Node>, // This has the last generated layer (In ordinary form)
+    combine_buffer: Option<Buffer<Node>>, // Holds a batch of data being combined
+    key_cache: KeyCache,
+    expander_streams: ExpanderStreams,
+    // The last generated layer is kept in both forms. The ordinary form is the input of
+    // the next layer, and the Montgomery form is the key once all layers are generated.
+    // The Montgomery buffer is also where every layer is read back from, as `Node`s are
+    // Montgomery: converting on device costs a kernel call per layer, while converting
+    // on host would cost a multiplication per node. Combining keeps the key on device
+    // for the same reason, as data is combined in Montgomery form.
+    current_layer: Buffer<OrdinaryNode>,
+    montgomery_layer: Buffer<Node>,
     pub config: Config,
 }
 
 impl GPU {
-    pub fn tree_builder(&mut self) -> &mut Option<TreeBuilder<U8>> {
-        &mut self.context.tree_builder
+    /// Set the maximum number of nodes combined at once, bounding the size of
+    /// the device buffer used for combining.
+    pub fn set_combine_batch_size(&mut self, combine_batch_size: usize) -> NSEResult<()> {
+        if combine_batch_size == 0 {
+            return Err(NSEError::InvalidCombineBatchSize);
+        }
+        self.combine_batch_size = combine_batch_size;
+        self.combine_buffer = None;
+        Ok(())
     }
 
-    fn replace_buffer(&mut self, buff: Buffer<Node>) {
-        std::mem::replace(&mut self.current_layer, buff);
+    /// Set the number of nodes whose expander parents are generated at once (A power of 2,
+    /// at most a window), bounding the size of the device buffer holding their bit-streams
+    /// (Which is released). Unless the whole window is generated at once, bit-streams are
+    /// generated again for every expander layer.
+    ///
+    /// Tiles are launched one at a time, so the local work sizes of expander kernels
+    /// should divide the tile size (Tuning with the tile size set first ensures it).
+    pub fn set_stream_tile_size(&mut self, tile_size: usize) -> NSEResult<()> {
+        if tile_size.count_ones() != 1 || tile_size > self.leaf_count() {
+            return Err(NSEError::InvalidStreamTileSize(format!(
+                "{} is not a power of 2 of at most {} nodes!",
+                tile_size,
+                self.leaf_count()
+            )));
+        }
+        for &kernel_name in [
+            "generate_streams",
+            "generate_expander",
+            "generate_expander_batch",
+        ]
+        .iter()
+        {
+            if let Some(&size) = self.context.local_work_sizes().get(kernel_name) {
+                if tile_size % size != 0 {
+                    return Err(NSEError::InvalidStreamTileSize(format!(
+                        "{} is not a multiple of the local work size {} of {}!",
+                        tile_size, size, kernel_name
+                    )));
+                }
+            }
+        }
+        self.expander_streams = ExpanderStreams {
+            tile_size,
+            tile: None,
+        };
+        Ok(())
+    }
+
+    pub fn stream_tile_size(&self) -> usize {
+        self.expander_streams.tile_size
     }
 
-    // Overwrite current layer
+    // Global work size every launch of a kernel is a multiple of, which its local work size
+    // should divide (Segments of arbitrary length are not considered).
+    pub(crate) fn kernel_global_size(&self, kernel_name: &str) -> usize {
+        match kernel_name {
+            "generate_streams" | "generate_expander" | "generate_expander_batch" => {
+                self.expander_streams.tile_size
+            }
+            _ => self.leaf_count(),
+        }
+    }
+
+    // Release the held bit-streams of expander parents, so that they are generated again
+    // by the next expander layer.
+    pub(crate) fn release_expander_streams(&mut self) {
+        self.expander_streams.tile = None;
+    }
+
+    /// Set the number of finalized key layers cached on device and on host (0 disables).
+    pub fn set_key_cache_capacity(&mut self, device_capacity: usize, host_capacity: usize) {
+        self.key_cache.device.set_capacity(device_capacity);
+        self.key_cache.host.set_capacity(host_capacity);
+    }
+
+    pub fn key_cache(&self) -> &KeyCache {
+        &self.key_cache
+    }
+
+    /// Timings of the operations run since profiling was enabled on the context (Or
+    /// since the last reset), `None` if profiling is disabled.
+    pub fn profile_report(&mut self) -> NSEResult<Option<ProfileReport>> {
+        match self.context.profiler.as_mut() {
+            Some(profiler) => Ok(Some(profiler.report()?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn reset_profile(&mut self) {
+        if let Some(profiler) = self.context.profiler.as_mut() {
+            profiler.reset();
+        }
+    }
+
+    pub fn context(&self) -> &GPUContext {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut GPUContext {
+        &mut self.context
+    }
+
+    // Cache the finalized key layer, which is the Montgomery form of current layer
+    pub(crate) fn store_key(&mut self, id: KeyId) -> NSEResult<()> {
+        if self.key_cache.device.is_enabled() {
+            let key = self.copy_current_layer()?;
+            self.key_cache.device.insert(id.clone(), key);
+        }
+        if self.key_cache.host.is_enabled() {
+            let mut key = Layer(vec![Node::default(); self.leaf_count()]);
+            self.context
+                .read_buffer(&self.montgomery_layer, 0, &mut key.0)?;
+            self.key_cache.host.insert(id, key);
+        }
+        Ok(())
+    }
+
+    // Copy the Montgomery form of current layer into a new buffer
+    pub(crate) fn copy_current_layer(&mut self) -> NSEResult<Buffer<Node>> {
+        let copy = self.context.create_buffer()?;
+        self.context.copy_buffer(&self.montgomery_layer, &copy)?;
+        Ok(copy)
+    }
+
+    // Stream `segment` through the combine buffer, one batch at a time. `kernel` is called
+    // per batch with the current key, the buffer holding the batch, and the offset and
+    // length of the batch.
+    fn combine_batches<F>(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        output: &mut [Node],
+        mut kernel: F,
+    ) -> NSEResult<()>
+    where
+        F: FnMut(&mut GPUContext, &Buffer<Node>, &Buffer<Node>, usize, usize) -> NSEResult<()>,
+    {
+        check_output_len(segment.len(), output)?;
+        if offset
+            .checked_add(segment.len())
+            .map_or(true, |end| end > self.leaf_count())
+        {
+            return Err(NSEError::SegmentOutOfRange {
+                offset,
+                len: segment.len(),
+            });
+        }
+        if segment.is_empty() {
+            return Ok(());
+        }
+
+        let batch_size = std::cmp::min(self.combine_batch_size, self.leaf_count());
+        let mut data = match self.combine_buffer.take() {
+            Some(buff) => buff,
+            None => self.context.create_buffer_with_len(batch_size)?,
+        };
+
+        for (i, (input, output)) in segment
+            .chunks(batch_size)
+            .zip(output.chunks_mut(batch_size))
+            .enumerate()
+        {
+            self.context.write_buffer(&mut data, 0, input)?;
+            kernel(
+                &mut self.context,
+                &self.montgomery_layer,
+                &data,
+                offset + i * batch_size,
+                input.len(),
+            )?;
+            self.context.read_buffer(&data, 0, output)?;
+        }
+
+        self.combine_buffer = Some(data);
+        Ok(())
+    }
+
+    // Decode `segment` with `from_key` and encode it with the key in current layer,
+    // without the decoded data ever leaving the device.
+    pub(crate) fn recombine_segment(
+        &mut self,
+        from_key: &Buffer<Node>,
+        offset: usize,
+        segment: &[Node],
+    ) -> NSEResult<Vec<Node>> {
+        let mut l = vec![Node::default(); segment.len()];
+        self.combine_batches(
+            offset,
+            segment,
+            &mut l,
+            |context, to_key, data, offset, len| {
+                call_kernel!(
+                    context,
+                    "recombine_segment",
+                    [offset, len],
+                    from_key,
+                    to_key,
+                    data
+                );
+                Ok(())
+            },
+        )?;
+        Ok(l)
+    }
+
+    // Load a cached key layer as the Montgomery form of current layer, returns false if not cached
+    pub(crate) fn load_key(&mut self, id: &KeyId) -> NSEResult<bool> {
+        if let Some(key) = self.key_cache.device.get(id) {
+            self.context.copy_buffer(key, &self.montgomery_layer)?;
+            return Ok(true);
+        }
+        if let Some(key) = self.key_cache.host.get(id) {
+            self.context
+                .write_buffer(&mut self.montgomery_layer, 0, &key.0)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    pub(crate) fn trees(&mut self) -> NSEResult<&mut TreeBackend> {
+        self.context
+            .trees
+            .as_mut()
+            .ok_or(NSEError::TreeBuildingDisabled)
+    }
+
+    // Start building the tree of a layer, trees are collected (Along with their layers)
+    // in submission order
+    pub(crate) fn submit_tree(&mut self, layer: Layer) -> NSEResult<()> {
+        self.trees()?.submit(layer)
+    }
+
+    pub(crate) fn collect_tree(&mut self) -> NSEResult<(Layer, Vec<Node>)> {
+        self.trees()?.collect()
+    }
+
+    // Build the tree of a layer through the `TreeBuilder` of the context
+    pub(crate) fn build_tree(&mut self, layer: &[Node]) -> NSEResult<Vec<Node>> {
+        self.trees()?.build(layer)
+    }
+
+    // Whether trees are built while the next layer is being generated
+    pub(crate) fn builds_trees_concurrently(&self) -> bool {
+        self.context
+            .trees
+            .as_ref()
+            .map(|t| t.is_concurrent())
+            .unwrap_or(false)
+    }
+
+    /// Start generating the layers of several windows at once, each layer of all
+    /// windows is generated in a single kernel launch.
+    pub fn start_batch(&mut self, windows: &[KeySpec]) -> NSEResult<()> {
+        if windows.is_empty() {
+            return Err(NSEError::InvalidBatch("Batch has no windows!".into()));
+        }
+        for w in windows.iter() {
+            self.config.check_window_index(w.window_index)?;
+        }
+        let ids = windows.iter().map(|w| w.replica_id).collect::<Vec<_>>();
+        let window_indices = windows.iter().map(|w| w.window_index).collect::<Vec<_>>();
+        self.batch = Some(WindowBatch {
+            ids: self.context.create_buffer_from_slice(&ids)?,
+            window_indices: self.context.create_buffer_from_slice(&window_indices)?,
+            current_layers: self
+                .context
+                .create_typed_buffer(windows.len() * self.leaf_count())?,
+            montgomery_layers: self
+                .context
+                .create_buffer_with_len(windows.len() * self.leaf_count())?,
+            len: windows.len(),
+        });
+        Ok(())
+    }
+
+    /// End the current batch, releasing its buffers.
+    pub fn end_batch(&mut self) {
+        self.batch = None;
+    }
+
+    /// Number of windows in the current batch.
+    pub fn batch_len(&self) -> usize {
+        self.batch.as_ref().map(|b| b.len).unwrap_or(0)
+    }
+
+    // Run the batched kernel of a layer, and return the generated layers of all windows
+    fn generate_batch_layers(&mut self, kind: BatchLayer) -> NSEResult<Vec<Layer>> {
+        let (degree, factor) = match kind {
+            BatchLayer::Mask => (0, 0),
+            BatchLayer::Expander(layer_index) => (self.expander_degree(layer_index)?, 0),
+            BatchLayer::Butterfly(layer_index) => (
+                self.butterfly_degree(layer_index)?,
+                self.config.butterfly_factor(layer_index),
+            ),
+        };
+        let leaf_count = self.leaf_count();
+        let batch = self
+            .batch
+            .as_mut()
+            .ok_or_else(|| NSEError::InvalidBatch("No batch is started!".into()))?;
+        let len = batch.len * leaf_count;
+        let ord_output = self.context.create_typed_buffer::<OrdinaryNode>(len)?;
+        match kind {
+            BatchLayer::Mask => call_kernel!(
+                self.context,
+                "generate_mask_batch",
+                [0, len],
+                &ord_output,
+                &batch.ids,
+                &batch.window_indices
+            ),
+            BatchLayer::Expander(layer_index) => {
+                let tile_size = self.expander_streams.tile_size;
+                for tile_offset in (0..leaf_count).step_by(tile_size) {
+                    let streams =
+                        self.expander_streams
+                            .tile(&mut self.context, &self.config, tile_offset)?;
+                    call_kernel!(
+                        self.context,
+                        "generate_expander_batch",
+                        [0, batch.len * tile_size],
+                        &batch.current_layers,
+                        &ord_output,
+                        streams,
+                        tile_offset as u32,
+                        tile_size as u32,
+                        &batch.ids,
+                        &batch.window_indices,
+                        layer_index as u32,
+                        degree as u32
+                    );
+                }
+            }
+            BatchLayer::Butterfly(layer_index) => call_kernel!(
+                self.context,
+                "generate_butterfly_batch",
+                [0, len],
+                &batch.current_layers,
+                &ord_output,
+                &batch.ids,
+                &batch.window_indices,
+                layer_index as u32,
+                degree as u32,
+                factor as u32
+            ),
+        }
+        call_kernel!(
+            self.context,
+            "generate_montgomery",
+            [0, len],
+            &ord_output,
+            &batch.montgomery_layers
+        );
+        let mut nodes = vec![Node::default(); len];
+        self.context
+            .read_buffer(&batch.montgomery_layers, 0, &mut nodes)?;
+        batch.current_layers = ord_output;
+        Ok(nodes
+            .chunks(leaf_count)
+            .map(|l| Layer(l.to_vec()))
+            .collect())
+    }
+
+    pub fn generate_mask_layers(&mut self) -> NSEResult<Vec<Layer>> {
+        self.generate_batch_layers(BatchLayer::Mask)
+    }
+
+    pub fn generate_expander_layers(&mut self, layer_index: usize) -> NSEResult<Vec<Layer>> {
+        self.generate_batch_layers(BatchLayer::Expander(layer_index))
+    }
+
+    pub fn generate_butterfly_layers(&mut self, layer_index: usize) -> NSEResult<Vec<Layer>> {
+        self.generate_batch_layers(BatchLayer::Butterfly(layer_index))
+    }
+
+    /// Combine a layer of every window of the batch with its finalized key.
+    pub fn combine_batch_layers(
+        &mut self,
+        layers: &[Layer],
+        is_decode: bool,
+    ) -> NSEResult<Vec<Layer>> {
+        let leaf_count = self.leaf_count();
+        let batch = self
+            .batch
+            .as_ref()
+            .ok_or_else(|| NSEError::InvalidBatch("No batch is started!".into()))?;
+        if layers.len() != batch.len || layers.iter().any(|l| l.0.len() != leaf_count) {
+            return Err(NSEError::InvalidBatch(format!(
+                "Expected {} layers of {} nodes!",
+                batch.len, leaf_count
+            )));
+        }
+        let mut nodes = layers
+            .iter()
+            .flat_map(|l| l.0.iter().cloned())
+            .collect::<Vec<_>>();
+        let data = self.context.create_buffer_from_slice(&nodes)?;
+        call_kernel!(
+            self.context,
+            "combine_segment",
+            [0, nodes.len()],
+            &batch.montgomery_layers,
+            &data,
+            is_decode as u32
+        );
+        self.context.read_buffer(&data, 0, &mut nodes)?;
+        Ok(nodes
+            .chunks(leaf_count)
+            .map(|l| Layer(l.to_vec()))
+            .collect())
+    }
+
+    // Degree of the expander layer at `layer_index` of the layer schedule
+    fn expander_degree(&self, layer_index: usize) -> NSEResult<usize> {
+        match self.config.layer_spec(layer_index) {
+            Some(LayerSpec::Expander { degree }) => Ok(degree),
+            _ => Err(NSEError::LayerKindMismatch(layer_index)),
+        }
+    }
+
+    // Degree of the butterfly layer at `layer_index` of the layer schedule
+    fn butterfly_degree(&self, layer_index: usize) -> NSEResult<usize> {
+        match self.config.layer_spec(layer_index) {
+            Some(LayerSpec::Butterfly { degree }) => Ok(degree),
+            _ => Err(NSEError::LayerKindMismatch(layer_index)),
+        }
+    }
+
+    // Make `ord_output` the current layer, and read its Montgomery form into `output`
+    fn replace_current_layer(
+        &mut self,
+        ord_output: Buffer<OrdinaryNode>,
+        output: &mut [Node],
+    ) -> NSEResult<()> {
+        call_kernel!(
+            self.context,
+            "generate_montgomery",
+            &ord_output,
+            &self.montgomery_layer
+        );
+        self.context
+            .read_buffer(&self.montgomery_layer, 0, output)?;
+        self.current_layer = ord_output;
+        Ok(())
+    }
+
+    // Overwrite current layer. Layers stored as bytes are parsed through `OrdinaryLayer`,
+    // and have to be converted into a `Layer` first.
     pub fn push_layer(&mut self, layer: &Layer) -> NSEResult<()> {
-        write_buffer(&mut self.current_layer, 0, &layer.0)?; // Push montgomery form in buffer
-        let ordinary = self.context.create_buffer()?; // Create new temp buffer
+        self.context
+            .write_buffer(&mut self.montgomery_layer, 0, &layer.0)?;
+        self.generate_ordinary()
+    }
+
+    /// Same as `push_layer`, with the nodes of the layer written by `fill`, which is called
+    /// with successive chunks of the layer along with their offset in the layer. With
+    /// pinned transfers, chunks are the mapped pinned buffer itself, so that a layer read
+    /// from elsewhere (E.g. parsed from a file) reaches the device without a host copy.
+    pub fn push_layer_with<F>(&mut self, fill: F) -> NSEResult<()>
+    where
+        F: FnMut(usize, &mut [Node]) -> NSEResult<()>,
+    {
+        let leaf_count = self.leaf_count();
+        self.context
+            .write_buffer_with(&mut self.montgomery_layer, 0, leaf_count, fill)?;
+        self.generate_ordinary()
+    }
+
+    // Convert the Montgomery form of current layer into its ordinary form
+    fn generate_ordinary(&mut self) -> NSEResult<()> {
         call_kernel!(
             self.context,
             "generate_ordinary",
-            &self.current_layer,
-            &ordinary
+            &self.montgomery_layer,
+            &self.current_layer
         );
-        self.replace_buffer(ordinary); // Current buffer has now the ordinary form
         Ok(())
     }
 }
 
 impl NarrowStackedExpander for GPU {
     fn new(mut context: GPUContext, config: Config) -> NSEResult<Self> {
-        let current_layer = context.create_buffer()?;
+        let current_layer = context.create_typed_buffer(config.num_nodes_window)?;
+        let montgomery_layer = context.create_buffer()?;
 
         Ok(GPU {
             context,
             current_layer,
+            montgomery_layer,
+            batch: None,
+            expander_streams: ExpanderStreams::new(&config),
             combine_batch_size: COMBINE_BATCH_SIZE,
+            combine_buffer: None,
+            key_cache: KeyCache::new(0, 0),
             config,
         })
     }
 
-    fn generate_mask_layer(
+    fn generate_mask_layer_into(
         &mut self,
         replica_id: ReplicaId,
-        window_index: usize,
-    ) -> NSEResult<Layer> {
-        let mut l = Layer(vec![Node::default(); self.leaf_count()]);
-        let ord_output = self.context.create_buffer()?;
+        window_index: u64,
+        output: &mut [Node],
+    ) -> NSEResult<()> {
+        self.config.check_window_index(window_index)?;
+        check_output_len(self.leaf_count(), output)?;
+        let ord_output = self
+            .context
+            .create_typed_buffer::<OrdinaryNode>(self.leaf_count())?;
         call_kernel!(
             self.context,
             "generate_mask",
             &ord_output,
             replica_id,
-            window_index as u32
-        );
-        call_kernel!(
-            self.context,
-            "generate_montgomery",
-            &ord_output,
-            &self.current_layer
+            window_index
         );
-        read_buffer(&self.current_layer, 0, &mut l.0)?;
-        self.replace_buffer(ord_output);
-        Ok(l)
+        self.replace_current_layer(ord_output, output)
     }
 
-    fn generate_expander_layer(
+    fn generate_expander_layer_into(
         &mut self,
         replica_id: ReplicaId,
-        window_index: usize,
+        window_index: u64,
         layer_index: usize,
-    ) -> NSEResult<Layer> {
-        let mut l = Layer(vec![Node::default(); self.leaf_count()]);
-        let ord_output = self.context.create_buffer()?;
-        call_kernel!(
-            self.context,
-            "generate_expander",
-            &self.current_layer,
-            &ord_output,
-            replica_id,
-            window_index as u32,
-            layer_index as u32
-        );
-        call_kernel!(
-            self.context,
-            "generate_montgomery",
-            &ord_output,
-            &self.current_layer
-        );
-        read_buffer(&self.current_layer, 0, &mut l.0)?;
-        self.replace_buffer(ord_output);
-        Ok(l)
+        output: &mut [Node],
+    ) -> NSEResult<()> {
+        self.config.check_window_index(window_index)?;
+        let degree = self.expander_degree(layer_index)?;
+        check_output_len(self.leaf_count(), output)?;
+        let ord_output = self
+            .context
+            .create_typed_buffer::<OrdinaryNode>(self.leaf_count())?;
+        let tile_size = self.expander_streams.tile_size;
+        for tile_offset in (0..self.leaf_count()).step_by(tile_size) {
+            let streams =
+                self.expander_streams
+                    .tile(&mut self.context, &self.config, tile_offset)?;
+            call_kernel!(
+                self.context,
+                "generate_expander",
+                [0, tile_size],
+                &self.current_layer,
+                &ord_output,
+                streams,
+                tile_offset as u32,
+                tile_size as u32,
+                replica_id,
+                window_index,
+                layer_index as u32,
+                degree as u32
+            );
+        }
+        self.replace_current_layer(ord_output, output)
     }
 
-    fn generate_butterfly_layer(
+    fn generate_butterfly_layer_into(
         &mut self,
         replica_id: ReplicaId,
-        window_index: usize,
+        window_index: u64,
         layer_index: usize,
-    ) -> NSEResult<Layer> {
-        let mut l = Layer(vec![Node::default(); self.leaf_count()]);
-        let ord_output = self.context.create_buffer()?;
+        output: &mut [Node],
+    ) -> NSEResult<()> {
+        self.config.check_window_index(window_index)?;
+        let degree = self.butterfly_degree(layer_index)?;
+        let factor = self.config.butterfly_factor(layer_index);
+        check_output_len(self.leaf_count(), output)?;
+        let ord_output = self
+            .context
+            .create_typed_buffer::<OrdinaryNode>(self.leaf_count())?;
         call_kernel!(
             self.context,
             "generate_butterfly",
             &self.current_layer,
             &ord_output,
             replica_id,
-            window_index as u32,
-            layer_index as u32
+            window_index,
+            layer_index as u32,
+            degree as u32,
+            factor as u32
         );
-        call_kernel!(
-            self.context,
-            "generate_montgomery",
-            &ord_output,
-            &self.current_layer
-        );
-        read_buffer(&self.current_layer, 0, &mut l.0)?;
-        self.replace_buffer(ord_output);
-        Ok(l)
+        self.replace_current_layer(ord_output, output)
     }
 
     fn finalize(&mut self) -> NSEResult<()> {
-        call_kernel!(self.context, "to_montgomery", &self.current_layer);
+        // The Montgomery form of the last layer, which is the key, is already kept
         Ok(())
     }
 
-    fn combine_segment(
+    fn combine_segment_into(
         &mut self,
         offset: usize,
         segment: &[Node],
         is_decode: bool,
-    ) -> NSEResult<Vec<Node>> {
-        // Montgomery form of mask is in kernel_buffer!
-        let mut l = vec![Node::default(); segment.len()];
-        let mut data = self.context.create_buffer()?;
-        write_buffer(&mut data, offset, &segment)?;
-        call_kernel!(
-            self.context,
-            "combine_segment",
-            &self.current_layer,
-            &data,
-            offset as u32,
-            segment.len() as u32,
-            is_decode as u32
-        );
-        read_buffer(&data, offset, &mut l)?;
-        Ok(l)
+        output: &mut [Node],
+    ) -> NSEResult<()> {
+        self.combine_batches(
+            offset,
+            segment,
+            output,
+            |context, key, data, offset, len| {
+                call_kernel!(
+                    context,
+                    "combine_segment",
+                    [offset, len],
+                    key,
+                    data,
+                    is_decode as u32
+                );
+                Ok(())
+            },
+        )
     }
 
     fn combine_batch_size(&self) -> usize {
@@ -286,18 +1206,14 @@ impl NarrowStackedExpander for GPU {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::LabelingHash;
     use ff::{Field, PrimeField};
     use paired::bls12_381::Fr;
 
-    const TEST_CONFIG: Config = Config {
-        k: 4,
-        num_nodes_window: 1024,
-        degree_expander: 96,
-        degree_butterfly: 4,
-        num_expander_layers: 4,
-        num_butterfly_layers: 3,
-    };
-    const TEST_WINDOW_INDEX: usize = 1234567890;
+    fn test_config() -> Config {
+        Config::new(4, 1024, 96, 4, 4, 3, LabelingHash::Sha256).unwrap()
+    }
+    const TEST_WINDOW_INDEX: u64 = 1234567890;
     const TEST_REPLICA_ID: ReplicaId = ReplicaId([123u8; 32]);
 
     pub fn accumulate(l: &Vec<Node>) -> Node {
@@ -318,8 +1234,8 @@ mod tests {
 
     #[test]
     fn test_generate_mask_layer() {
-        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
-        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
         let l = gpu
             .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
             .unwrap();
@@ -334,16 +1250,46 @@ mod tests {
 
     #[test]
     fn test_generate_expander_layer() {
-        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
-        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
-        gpu.push_layer(&incrementing_layer(123, TEST_CONFIG.num_nodes_window))
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        // Whole window at once, then in tiles of bit-streams
+        for &tile_size in [test_config().num_nodes_window, 256, 64].iter() {
+            gpu.set_stream_tile_size(tile_size).unwrap();
+            gpu.push_layer(&incrementing_layer(123, test_config().num_nodes_window))
+                .unwrap();
+            let l = gpu
+                .generate_expander_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX, 2)
+                .unwrap();
+            assert_eq!(
+                Fr::from_str(
+                    "22705938218269600582111888137759768785425241059162326580787572603300938432305"
+                )
+                .unwrap(),
+                accumulate(&l.0).0
+            );
+        }
+        assert!(gpu.set_stream_tile_size(96).is_err());
+        assert!(gpu
+            .set_stream_tile_size(2 * test_config().num_nodes_window)
+            .is_err());
+        gpu.context_mut()
+            .set_local_work_size("generate_expander", Some(128));
+        assert!(gpu.set_stream_tile_size(64).is_err());
+        assert_eq!(gpu.stream_tile_size(), 64);
+    }
+
+    #[test]
+    fn test_generate_butterfly_layer() {
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        gpu.push_layer(&incrementing_layer(345, test_config().num_nodes_window))
             .unwrap();
         let l = gpu
-            .generate_expander_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX, 2)
+            .generate_butterfly_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX, 2)
             .unwrap();
         assert_eq!(
             Fr::from_str(
-                "22705938218269600582111888137759768785425241059162326580787572603300938432305"
+                "957721749774935859819530955474230185920900559990452793231841687285976045738"
             )
             .unwrap(),
             accumulate(&l.0).0
@@ -351,29 +1297,137 @@ mod tests {
     }
 
     #[test]
-    fn test_generate_butterfly_layer() {
-        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
-        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
-        gpu.push_layer(&incrementing_layer(345, TEST_CONFIG.num_nodes_window))
+    fn test_forked_context() {
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let forked = ctx.fork().unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let mut forked_gpu = GPU::new(forked, test_config()).unwrap();
+
+        // Interleave the layers of both contexts
+        let mask = gpu
+            .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
             .unwrap();
-        let l = gpu
-            .generate_butterfly_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX, 2)
+        let forked_mask = forked_gpu
+            .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+            .unwrap();
+        let expander = gpu
+            .generate_expander_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX, 2)
+            .unwrap();
+        let forked_expander = forked_gpu
+            .generate_expander_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX, 2)
             .unwrap();
+        assert_eq!(mask, forked_mask);
+        assert_eq!(expander, forked_expander);
+    }
+
+    #[test]
+    fn test_pinned_transfers() {
+        let mut rng = rand::thread_rng();
+        let data = Layer::random(&mut rng, test_config().num_nodes_window);
+
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let mask = gpu
+            .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+            .unwrap();
+        let combined = gpu.combine_segment(100, &data.0[..200], false).unwrap();
+        gpu.push_layer_with(|start, chunk| {
+            chunk.copy_from_slice(&data.0[start..start + chunk.len()]);
+            Ok(())
+        })
+        .unwrap();
+        gpu.finalize().unwrap();
         assert_eq!(
-            Fr::from_str(
-                "957721749774935859819530955474230185920900559990452793231841687285976045738"
-            )
-            .unwrap(),
-            accumulate(&l.0).0
+            gpu.combine_segment(0, &data.0, true).unwrap(),
+            vec![Node::default(); test_config().num_nodes_window]
+        );
+
+        let mut ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        ctx.set_pinned_transfers(true).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        gpu.set_combine_batch_size(64).unwrap();
+        assert!(gpu.context().has_pinned_transfers());
+        assert_eq!(
+            gpu.generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                .unwrap(),
+            mask
+        );
+        assert_eq!(
+            gpu.combine_segment(100, &data.0[..200], false).unwrap(),
+            combined
+        );
+        gpu.push_layer(&data).unwrap();
+        gpu.finalize().unwrap();
+        assert_eq!(
+            gpu.combine_segment(0, &data.0, true).unwrap(),
+            vec![Node::default(); test_config().num_nodes_window]
         );
+
+        // Layers filled in place, in chunks of the pinned buffer
+        let mut offsets = Vec::new();
+        gpu.push_layer_with(|start, chunk| {
+            offsets.push(start);
+            chunk.copy_from_slice(&data.0[start..start + chunk.len()]);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(offsets, vec![0]);
+        gpu.finalize().unwrap();
+        assert_eq!(
+            gpu.combine_segment(0, &data.0, true).unwrap(),
+            vec![Node::default(); test_config().num_nodes_window]
+        );
+    }
+
+    #[test]
+    fn test_profiling() {
+        let ctx = GPUContext::new_profiled(
+            utils::default_device().unwrap(),
+            test_config(),
+            TreeOptions::Disabled,
+        )
+        .unwrap();
+        assert!(ctx.fork().unwrap().is_profiling());
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        for _ in 0..2 {
+            gpu.generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                .unwrap();
+        }
+
+        let report = gpu.profile_report().unwrap().unwrap();
+        for &name in ["generate_mask", "generate_montgomery", "read_buffer"].iter() {
+            assert_eq!(report.operations[name].count, 2);
+        }
+
+        gpu.reset_profile();
+        assert!(gpu.profile_report().unwrap().unwrap().operations.is_empty());
+    }
+
+    #[test]
+    fn test_window_index_range() {
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let low = gpu.generate_mask_layer(TEST_REPLICA_ID, 5).unwrap();
+        let high = gpu
+            .generate_mask_layer(TEST_REPLICA_ID, (1 << 32) + 5)
+            .unwrap();
+        assert!(low != high);
+
+        let max_window_index = u64::max_value() / test_config().num_nodes_window as u64;
+        assert!(gpu
+            .generate_mask_layer(TEST_REPLICA_ID, max_window_index)
+            .is_ok());
+        assert!(gpu
+            .generate_mask_layer(TEST_REPLICA_ID, max_window_index + 1)
+            .is_err());
     }
 
     #[test]
     fn test_combine_layer() {
-        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
-        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
-        let data = incrementing_layer(567, TEST_CONFIG.num_nodes_window);
-        let mask = incrementing_layer(234, TEST_CONFIG.num_nodes_window);
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let data = incrementing_layer(567, test_config().num_nodes_window);
+        let mask = incrementing_layer(234, test_config().num_nodes_window);
         gpu.push_layer(&mask).unwrap();
         gpu.finalize().unwrap();
         let encode = gpu.combine_segment(0, &data.0, false).unwrap();
@@ -381,4 +1435,49 @@ mod tests {
         assert_eq!(Fr::from_str("1867776").unwrap(), accumulate(&encode).0);
         assert_eq!(Fr::from_str("340992").unwrap(), accumulate(&decode).0);
     }
+
+    #[test]
+    fn test_combine_segment() {
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let data = incrementing_layer(567, test_config().num_nodes_window);
+        let mask = incrementing_layer(234, test_config().num_nodes_window);
+        gpu.push_layer(&mask).unwrap();
+        gpu.finalize().unwrap();
+        let encode = gpu.combine_segment(0, &data.0, false).unwrap();
+
+        let (offset, len) = (100, 300);
+        assert_eq!(
+            gpu.combine_segment(offset, &data.0[offset..offset + len], false)
+                .unwrap(),
+            &encode[offset..offset + len]
+        );
+        assert!(gpu
+            .combine_segment(test_config().num_nodes_window - 10, &data.0[..11], false)
+            .is_err());
+        assert!(gpu
+            .combine_segment(usize::max_value(), &data.0[..1], false)
+            .is_err());
+    }
+
+    #[test]
+    fn test_combine_batches() {
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let data = incrementing_layer(567, test_config().num_nodes_window);
+        let mask = incrementing_layer(234, test_config().num_nodes_window);
+        gpu.push_layer(&mask).unwrap();
+        gpu.finalize().unwrap();
+        let encode = gpu.combine_segment(0, &data.0, false).unwrap();
+
+        gpu.set_combine_batch_size(100).unwrap();
+        assert_eq!(gpu.combine_batch_size(), 100);
+        assert!(gpu.set_combine_batch_size(0).is_err());
+        assert_eq!(gpu.combine_batch_size(), 100);
+        assert_eq!(gpu.combine_segment(0, &data.0, false).unwrap(), encode);
+        assert_eq!(
+            gpu.combine_segment(50, &data.0[50..450], false).unwrap(),
+            &encode[50..450]
+        );
+    }
 }
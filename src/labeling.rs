@@ -0,0 +1,257 @@
+//! Labeling hashes, and a CPU implementation of layer generation.
+//!
+//! Nodes are labeled with the hash of a prefix block (Layer index, absolute node index
+//! and replica id), followed by blocks of pairs of parent nodes. The digest is read as a
+//! little-endian integer with its two most significant bits zeroed.
+
+use crate::{sources, Config, Layer, LayerSpec, NSEError, NSEResult, Node, ReplicaId, NODE_SIZE};
+use ff::Field;
+use sha2::{Digest, Sha256};
+
+/// Hash function used for labeling nodes.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum LabelingHash {
+    Sha256,
+    Blake2s,
+}
+
+impl LabelingHash {
+    /// Hash `message` into a 32-byte digest.
+    pub fn hash(&self, message: &[u8]) -> [u8; 32] {
+        let mut digest = [0u8; 32];
+        match self {
+            LabelingHash::Sha256 => digest.copy_from_slice(&Sha256::digest(message)),
+            LabelingHash::Blake2s => {
+                digest.copy_from_slice(blake2s_simd::blake2s(message).as_bytes())
+            }
+        }
+        digest
+    }
+
+    // Label of a node, whose hashed data is `message`
+    fn label(&self, message: &[u8]) -> Node {
+        let mut digest = self.hash(message);
+        digest[31] &= 0b0011_1111;
+        Node::from_bytes(&digest).expect("Labels are always smaller than the modulus!")
+    }
+}
+
+// First block hashed by every label
+fn hash_prefix(layer_index: usize, node_absolute_index: u64, replica_id: ReplicaId) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(64);
+    prefix.extend_from_slice(&(layer_index as u32).to_be_bytes());
+    prefix.extend_from_slice(&node_absolute_index.to_be_bytes());
+    prefix.resize(32, 0);
+    prefix.extend_from_slice(&replica_id.0);
+    prefix
+}
+
+// Bit-stream of a node, from which its expander parents are read (Always SHA-256)
+fn bit_stream(config: &Config, node: usize) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for i in 0..sources::stream_hash_count(config) {
+        let mut data = [0u8; 64];
+        data[..4].copy_from_slice(&(node as u32).to_be_bytes());
+        data[4..8].copy_from_slice(&(i as u32).to_be_bytes());
+        stream.extend_from_slice(&Sha256::digest(&data));
+    }
+    stream
+}
+
+// Check that `input` is a whole window layer
+fn check_input(config: &Config, input: &Layer) -> NSEResult<()> {
+    if input.0.len() != config.num_nodes_window {
+        return Err(NSEError::LayerLengthMismatch {
+            expected: config.num_nodes_window,
+            actual: input.0.len(),
+        });
+    }
+    Ok(())
+}
+
+// `i`th expanded parent of a node, with its bit-stream
+fn expanded_parent(config: &Config, stream: &[u8], i: usize) -> usize {
+    let k = config.k as usize;
+    let byte_size = sources::bit_size(config) / 8;
+    let parent = stream[(i / k) * byte_size..(i / k + 1) * byte_size]
+        .iter()
+        .rev()
+        .fold(0, |acc, &b| (acc << 8) | b as usize);
+    parent * k + i % k
+}
+
+/// Generate the mask layer of a window on CPU.
+pub fn mask_layer(config: &Config, replica_id: ReplicaId, window_index: u64) -> NSEResult<Layer> {
+    sources::check_config(config)?;
+    config.check_window_index(window_index)?;
+    let n = config.num_nodes_window;
+    Ok(Layer(
+        (0..n)
+            .map(|node| {
+                let node_absolute_index = window_index * n as u64 + node as u64;
+                config
+                    .hash
+                    .label(&hash_prefix(1, node_absolute_index, replica_id))
+            })
+            .collect(),
+    ))
+}
+
+/// Generate the expander layer at `layer_index` of the layer schedule on CPU, from the
+/// previous layer.
+pub fn expander_layer(
+    config: &Config,
+    replica_id: ReplicaId,
+    window_index: u64,
+    layer_index: usize,
+    input: &Layer,
+) -> NSEResult<Layer> {
+    sources::check_config(config)?;
+    config.check_window_index(window_index)?;
+    let degree = match config.layer_spec(layer_index) {
+        Some(LayerSpec::Expander { degree }) => degree,
+        _ => return Err(NSEError::LayerKindMismatch(layer_index)),
+    };
+    check_input(config, input)?;
+    let n = config.num_nodes_window;
+    let k = config.k as usize;
+    Ok(Layer(
+        (0..n)
+            .map(|node| {
+                let stream = bit_stream(config, node);
+                let mut message = hash_prefix(
+                    layer_index,
+                    window_index * n as u64 + node as u64,
+                    replica_id,
+                );
+                for i in 0..degree {
+                    let mut x = Node::default();
+                    for j in 0..k {
+                        x.0.add_assign(
+                            &input.0[expanded_parent(config, &stream, i + j * degree)].0,
+                        );
+                    }
+                    message.extend_from_slice(&x.to_bytes());
+                }
+                config.hash.label(&message)
+            })
+            .collect(),
+    ))
+}
+
+/// Generate the butterfly layer at `layer_index` of the layer schedule on CPU, from the
+/// previous layer.
+pub fn butterfly_layer(
+    config: &Config,
+    replica_id: ReplicaId,
+    window_index: u64,
+    layer_index: usize,
+    input: &Layer,
+) -> NSEResult<Layer> {
+    sources::check_config(config)?;
+    config.check_window_index(window_index)?;
+    let degree = match config.layer_spec(layer_index) {
+        Some(LayerSpec::Butterfly { degree }) => degree,
+        _ => return Err(NSEError::LayerKindMismatch(layer_index)),
+    };
+    check_input(config, input)?;
+    let factor = config.butterfly_factor(layer_index);
+    let n = config.num_nodes_window;
+    Ok(Layer(
+        (0..n)
+            .map(|node| {
+                let mut message = Vec::with_capacity((degree + 2) * NODE_SIZE);
+                message.extend(hash_prefix(
+                    layer_index,
+                    window_index * n as u64 + node as u64,
+                    replica_id,
+                ));
+                for i in 0..degree {
+                    let parent = (node + i * factor) & (n - 1);
+                    message.extend_from_slice(&input.0[parent].to_bytes());
+                }
+                config.hash.label(&message)
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GPUContext, NarrowStackedExpander, TreeOptions, GPU};
+
+    fn test_config() -> Config {
+        Config::new(2, 512, 96, 4, 4, 3, LabelingHash::Sha256).unwrap()
+    }
+
+    #[test]
+    fn test_labeling_hashes() {
+        assert_eq!(
+            hex::encode(LabelingHash::Sha256.hash(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex::encode(LabelingHash::Blake2s.hash(b"abc")),
+            "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982"
+        );
+    }
+
+    #[test]
+    fn test_mask_layer_blake2s() {
+        let config = Config {
+            hash: LabelingHash::Blake2s,
+            ..test_config()
+        };
+        let mask = mask_layer(&config, ReplicaId([1u8; 32]), 3).unwrap();
+        assert_eq!(
+            hex::encode(mask.0[0].to_bytes()),
+            "466182ac7541117a003c1434ce3b3487c091f8785a41d87666e1d3be3be55b22"
+        );
+        assert_eq!(
+            hex::encode(mask.0[511].to_bytes()),
+            "573c014d1a1133c916df75e2ec7f9d8fc827d15b7c353ee1462a79126a05801e"
+        );
+    }
+
+    #[test]
+    fn test_cpu_layers() {
+        let mut rng = rand::thread_rng();
+        let replica_id = ReplicaId::random(&mut rng);
+        let window_index = 1234567890;
+        for &hash in [LabelingHash::Sha256, LabelingHash::Blake2s].iter() {
+            let config = Config {
+                hash,
+                ..test_config()
+            };
+            let ctx = GPUContext::default(config.clone(), TreeOptions::Disabled).unwrap();
+            let mut gpu = GPU::new(ctx, config.clone()).unwrap();
+
+            let mask = gpu.generate_mask_layer(replica_id, window_index).unwrap();
+            assert_eq!(mask, mask_layer(&config, replica_id, window_index).unwrap());
+
+            let expander = gpu
+                .generate_expander_layer(replica_id, window_index, 2)
+                .unwrap();
+            assert_eq!(
+                expander,
+                expander_layer(&config, replica_id, window_index, 2, &mask).unwrap()
+            );
+
+            let input = Layer::random(&mut rng, config.num_nodes_window);
+            gpu.push_layer(&input).unwrap();
+            let butterfly = gpu
+                .generate_butterfly_layer(replica_id, window_index, 5)
+                .unwrap();
+            assert_eq!(
+                butterfly,
+                butterfly_layer(&config, replica_id, window_index, 5, &input).unwrap()
+            );
+        }
+        assert!(mask_layer(&test_config(), replica_id, u64::max_value()).is_err());
+
+        let short = Layer::random(&mut rng, test_config().num_nodes_window / 2);
+        assert!(expander_layer(&test_config(), replica_id, window_index, 2, &short).is_err());
+        assert!(butterfly_layer(&test_config(), replica_id, window_index, 5, &short).is_err());
+    }
+}
@@ -1,16 +1,39 @@
+mod cache;
+mod commitment;
 mod error;
+pub mod fr32;
 mod gpu;
+pub mod labeling;
+mod merkle;
 mod pool;
+mod profile;
 mod sources;
+mod tree;
+mod tune;
 pub mod utils;
 
+pub use cache::*;
+pub use commitment::*;
 pub use error::*;
-use ff::{Field, PrimeField};
+use ff::{Field, PrimeField, PrimeFieldDecodingError};
 pub use gpu::*;
-use neptune::tree_builder::TreeBuilderTrait;
+pub use labeling::LabelingHash;
+pub use merkle::*;
+use ocl::Buffer;
 use paired::bls12_381::{Fr, FrRepr};
 pub use pool::*;
+pub use profile::*;
 use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Read, Write};
+use std::iter;
+use std::mem;
+use std::str::FromStr;
+use std::sync::Arc;
+pub use tree::*;
+pub use tune::*;
 
 // TODO: Move these constants into configuration of GPU, Sealer, KeyGenerator, etc.
 const COMBINE_BATCH_SIZE: usize = 500000;
@@ -27,9 +50,23 @@ impl Node {
         Node(Fr::random(rng))
     }
 
+    /// Parse a node from its canonical (Non-Montgomery) little-endian representation.
+    pub fn from_bytes(bytes: &[u8; NODE_SIZE]) -> Result<Self, PrimeFieldDecodingError> {
+        assert_eq!(std::mem::size_of::<FrRepr>(), NODE_SIZE);
+        Ok(Node(Fr::from_repr(unsafe {
+            std::mem::transmute::<[u8; NODE_SIZE], FrRepr>(*bytes)
+        })?))
+    }
+
+    /// Canonical (Non-Montgomery) little-endian representation of the node.
+    pub fn to_bytes(&self) -> [u8; NODE_SIZE] {
+        assert_eq!(std::mem::size_of::<FrRepr>(), NODE_SIZE);
+        unsafe { std::mem::transmute::<FrRepr, [u8; NODE_SIZE]>(self.0.into_repr()) }
+    }
+
     /// Convert a slice of `Node`s to a slice of `Fr`s.
     /// This conversion is accurate because `Node`s are in Montgomery Form.
-    fn as_frs<'a>(nodes: &'a [Node]) -> &'a [Fr] {
+    pub(crate) fn as_frs<'a>(nodes: &'a [Node]) -> &'a [Fr] {
         assert_eq!(
             std::mem::size_of::<Fr>(),
             std::mem::size_of::<Node>(),
@@ -41,7 +78,7 @@ impl Node {
 
     /// Convert a slice of `Fr`s to a slice of `Node`s.
     /// This conversion is accurate because `Node`s are in Montgomery Form.
-    fn from_frs<'a>(frs: &'a [Fr]) -> &'a [Node] {
+    pub(crate) fn from_frs<'a>(frs: &'a [Fr]) -> &'a [Node] {
         assert_eq!(
             std::mem::size_of::<Fr>(),
             std::mem::size_of::<Node>(),
@@ -58,7 +95,13 @@ impl Default for Node {
     }
 }
 
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+#[repr(transparent)]
+/// A node in ordinary (Non-Montgomery) form, as the kernels take it as input of a layer,
+/// and as it is stored as bytes. It is not interchangeable with `Node`.
+pub struct OrdinaryNode(pub FrRepr);
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub struct ReplicaId(pub [u8; 32]);
 
 impl Default for ReplicaId {
@@ -71,39 +114,111 @@ impl ReplicaId {
     pub fn random<R: RngCore>(rng: &mut R) -> Self {
         ReplicaId(rng.gen())
     }
+
+    /// Derives the replica id of a sector the same way Filecoin does (`generate_replica_id`
+    /// of storage-proofs), i.e. `SHA256(prover_id || sector_id || ticket || comm_d || porep_seed)`
+    /// (`sector_id` in big-endian), with the two most significant bits zeroed so that it is
+    /// a valid field element.
+    pub fn derive(
+        prover_id: &[u8; 32],
+        sector_id: u64,
+        ticket: &[u8; 32],
+        comm_d: Node,
+        porep_seed: &[u8; 32],
+    ) -> Self {
+        let hash = Sha256::new()
+            .chain(&prover_id[..])
+            .chain(&sector_id.to_be_bytes()[..])
+            .chain(&ticket[..])
+            .chain(&comm_d.to_bytes()[..])
+            .chain(&porep_seed[..])
+            .result();
+        let mut id = [0u8; 32];
+        id.copy_from_slice(&hash[..]);
+        id[31] &= 0b0011_1111;
+        ReplicaId(id)
+    }
+}
+
+impl fmt::Display for ReplicaId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
 }
 
+impl FromStr for ReplicaId {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut id = [0u8; 32];
+        hex::decode_to_slice(s, &mut id)?;
+        Ok(ReplicaId(id))
+    }
+}
+
+/// A layer of `Node`s (I.e. in Montgomery form), as layers are generated, combined and
+/// pushed.
 #[derive(PartialEq, Debug, Clone, Default)]
 pub struct Layer(pub Vec<Node>);
 
-impl From<&Vec<u8>> for Layer {
-    fn from(data: &Vec<u8>) -> Self {
-        assert_eq!(std::mem::size_of::<FrRepr>(), NODE_SIZE);
-        let mut nodes = Vec::with_capacity(data.len() / NODE_SIZE);
-        let mut temp = [0u8; NODE_SIZE];
-        for slice in data.chunks_exact(NODE_SIZE) {
-            temp.copy_from_slice(&slice[..]);
-            nodes.push(Node(
-                Fr::from_repr(unsafe { std::mem::transmute::<[u8; NODE_SIZE], FrRepr>(temp) })
-                    .unwrap(),
-            ));
+/// A layer in ordinary form. Layers are only serialized into (And parsed from) bytes in
+/// this form, and have to be converted into a `Layer` (Which validates their nodes)
+/// before being used.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct OrdinaryLayer(pub Vec<OrdinaryNode>);
+
+impl From<&Layer> for OrdinaryLayer {
+    fn from(layer: &Layer) -> Self {
+        OrdinaryLayer(
+            layer
+                .0
+                .iter()
+                .map(|n| OrdinaryNode(n.0.into_repr()))
+                .collect(),
+        )
+    }
+}
+
+impl TryFrom<&OrdinaryLayer> for Layer {
+    type Error = NSEError;
+
+    fn try_from(layer: &OrdinaryLayer) -> NSEResult<Self> {
+        Ok(Layer(
+            layer
+                .0
+                .iter()
+                .map(|n| Ok(Node(Fr::from_repr(n.0)?)))
+                .collect::<NSEResult<Vec<_>>>()?,
+        ))
+    }
+}
+
+/// Parses little-endian nodes.
+impl TryFrom<&[u8]> for OrdinaryLayer {
+    type Error = NSEError;
+
+    fn try_from(data: &[u8]) -> NSEResult<Self> {
+        if data.len() % NODE_SIZE != 0 {
+            return Err(NSEError::PartialNode);
         }
-        Layer(nodes)
+        let mut temp = [0u8; NODE_SIZE];
+        Ok(OrdinaryLayer(
+            data.chunks_exact(NODE_SIZE)
+                .map(|slice| {
+                    temp.copy_from_slice(slice);
+                    OrdinaryNode(unsafe { mem::transmute::<[u8; NODE_SIZE], FrRepr>(temp) })
+                })
+                .collect(),
+        ))
     }
 }
 
-impl From<&Layer> for Vec<u8> {
-    fn from(layer: &Layer) -> Self {
-        assert_eq!(std::mem::size_of::<FrRepr>(), NODE_SIZE);
+/// Serializes nodes in little-endian.
+impl From<&OrdinaryLayer> for Vec<u8> {
+    fn from(layer: &OrdinaryLayer) -> Self {
         let mut ret = Vec::with_capacity(layer.0.len() * NODE_SIZE);
         for n in layer.0.iter() {
-            ret.extend(unsafe {
-                std::slice::from_raw_parts(
-                    &n.0.into_repr() as *const FrRepr as *const u8,
-                    std::mem::size_of::<FrRepr>(),
-                )
-                .to_vec()
-            });
+            ret.extend_from_slice(&unsafe { mem::transmute::<FrRepr, [u8; NODE_SIZE]>(n.0) });
         }
         ret
     }
@@ -112,16 +227,76 @@ impl From<&Layer> for Vec<u8> {
 #[derive(PartialEq, Debug, Clone)]
 pub struct SealerInput {
     pub replica_id: ReplicaId,
-    pub window_index: usize,
+    pub window_index: u64,
     pub original_data: Layer,
 }
 
+/// Identifies the key of a window.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct KeySpec {
+    pub replica_id: ReplicaId,
+    pub window_index: u64,
+}
+
+/// Identifies a layer of a window. Key layers are generated in order: the mask layer,
+/// then expander and butterfly layers (Each numbered from 1 within its kind). The
+/// replica layer takes the place of the last butterfly layer in the output of sealers.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum LayerIndex {
+    Mask,
+    Expander(usize),
+    Butterfly(usize),
+    Replica,
+}
+
+/// Kind of a key layer, along with its degree, in the layer schedule of a `Config`.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum LayerSpec {
+    Mask,
+    Expander { degree: usize },
+    Butterfly { degree: usize },
+}
+
+/// Formats layers as `M`, `E<degree>` or `B<degree>` (E.g. `E96`).
+impl fmt::Display for LayerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LayerSpec::Mask => write!(f, "M"),
+            LayerSpec::Expander { degree } => write!(f, "E{}", degree),
+            LayerSpec::Butterfly { degree } => write!(f, "B{}", degree),
+        }
+    }
+}
+
+impl FromStr for LayerSpec {
+    type Err = NSEError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || NSEError::InvalidConfig(format!("Invalid layer: {}", s));
+        let degree = || s[1..].parse::<usize>().map_err(|_| invalid());
+        match s.chars().next() {
+            Some('M') if s.len() == 1 => Ok(LayerSpec::Mask),
+            Some('E') => Ok(LayerSpec::Expander { degree: degree()? }),
+            Some('B') => Ok(LayerSpec::Butterfly { degree: degree()? }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct LayerOutput {
+    pub index: LayerIndex,
     pub base: Layer,
     pub tree: Vec<Node>,
 }
 
+impl LayerOutput {
+    /// Root of the layer tree, `None` if trees were not built.
+    pub fn root(&self) -> Option<Node> {
+        self.tree.last().cloned()
+    }
+}
+
 impl Layer {
     pub fn random<R: RngCore>(rng: &mut R, node_count: usize) -> Self {
         Layer((0..node_count).map(|_| Node::random(rng)).collect())
@@ -130,23 +305,59 @@ impl Layer {
 
 pub trait NarrowStackedExpander: Sized {
     fn new(context: GPUContext, config: Config) -> NSEResult<Self>;
+
+    // `_into` variants write the generated layer into `output` (Which should have
+    // `leaf_count()` nodes), instead of allocating it.
+    fn generate_mask_layer_into(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: u64,
+        output: &mut [Node],
+    ) -> NSEResult<()>;
+    fn generate_expander_layer_into(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: u64,
+        layer_index: usize,
+        output: &mut [Node],
+    ) -> NSEResult<()>;
+    fn generate_butterfly_layer_into(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: u64,
+        layer_index: usize,
+        output: &mut [Node],
+    ) -> NSEResult<()>;
+
     fn generate_mask_layer(
         &mut self,
         replica_id: ReplicaId,
-        window_index: usize,
-    ) -> NSEResult<Layer>;
+        window_index: u64,
+    ) -> NSEResult<Layer> {
+        let mut l = Layer(vec![Node::default(); self.leaf_count()]);
+        self.generate_mask_layer_into(replica_id, window_index, &mut l.0)?;
+        Ok(l)
+    }
     fn generate_expander_layer(
         &mut self,
         replica_id: ReplicaId,
-        window_index: usize,
+        window_index: u64,
         layer_index: usize,
-    ) -> NSEResult<Layer>;
+    ) -> NSEResult<Layer> {
+        let mut l = Layer(vec![Node::default(); self.leaf_count()]);
+        self.generate_expander_layer_into(replica_id, window_index, layer_index, &mut l.0)?;
+        Ok(l)
+    }
     fn generate_butterfly_layer(
         &mut self,
         replica_id: ReplicaId,
-        window_index: usize,
+        window_index: u64,
         layer_index: usize,
-    ) -> NSEResult<Layer>;
+    ) -> NSEResult<Layer> {
+        let mut l = Layer(vec![Node::default(); self.leaf_count()]);
+        self.generate_butterfly_layer_into(replica_id, window_index, layer_index, &mut l.0)?;
+        Ok(l)
+    }
     fn finalize(&mut self) -> NSEResult<()>;
     // Combine functions need to get `&mut self`, as they modify internal state of GPU buffers
     fn combine_layer(&mut self, layer: &Layer, is_decode: bool) -> NSEResult<Layer> {
@@ -157,35 +368,188 @@ pub trait NarrowStackedExpander: Sized {
         offset: usize,
         segment: &[Node],
         is_decode: bool,
-    ) -> NSEResult<Vec<Node>>;
+    ) -> NSEResult<Vec<Node>> {
+        let mut l = vec![Node::default(); segment.len()];
+        self.combine_segment_into(offset, segment, is_decode, &mut l)?;
+        Ok(l)
+    }
+    /// Same as `combine_segment`, writing the results into `output` (Which should have
+    /// as many nodes as `segment`).
+    fn combine_segment_into(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        is_decode: bool,
+        output: &mut [Node],
+    ) -> NSEResult<()>;
     fn combine_batch_size(&self) -> usize;
     fn leaf_count(&self) -> usize;
 }
 
-// NOTES:
-// layers are 1-indexed,
+// Fill `buffer` as much as possible, returns less than `buffer.len()` only on EOF
+fn read_chunk<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
 
 /// The configuration parameters for NSE.
-#[derive(Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct Config {
     /// Batch hashing factor.
     pub k: u32,
     /// Number of nodes per window
     pub num_nodes_window: usize,
-    /// Degree of the expander graph.
-    pub degree_expander: usize,
-    /// Degree of the butterfly graph.
-    pub degree_butterfly: usize,
-    /// Number of expander layers.
-    pub num_expander_layers: usize, // 8
-    /// Number of butterfly layers.
-    pub num_butterfly_layers: usize, // 7
+    /// Kinds and degrees of the key layers of a window, in generation order. Shared, as
+    /// configs are cloned by every sealer and worker.
+    pub schedule: Arc<[LayerSpec]>,
+    /// Hash function labeling the nodes of layers.
+    pub hash: LabelingHash,
+}
+
+impl Config {
+    /// Config of the default layer schedule: A mask layer, then `num_expander_layers - 1`
+    /// expander layers of degree `degree_expander`, then `num_butterfly_layers` butterfly
+    /// layers of degree `degree_butterfly`.
+    pub fn new(
+        k: u32,
+        num_nodes_window: usize,
+        degree_expander: usize,
+        degree_butterfly: usize,
+        num_expander_layers: usize,  // 8
+        num_butterfly_layers: usize, // 7
+        hash: LabelingHash,
+    ) -> NSEResult<Self> {
+        let schedule = iter::once(LayerSpec::Mask)
+            .chain(
+                iter::repeat(LayerSpec::Expander {
+                    degree: degree_expander,
+                })
+                .take(num_expander_layers.saturating_sub(1)),
+            )
+            .chain(
+                iter::repeat(LayerSpec::Butterfly {
+                    degree: degree_butterfly,
+                })
+                .take(num_butterfly_layers),
+            )
+            .collect::<Vec<_>>();
+        Self::with_schedule(k, num_nodes_window, &schedule, hash)
+    }
+
+    /// Config of an explicit layer schedule, returning `InvalidConfig` if the parameters
+    /// or the schedule are not supported.
+    pub fn with_schedule(
+        k: u32,
+        num_nodes_window: usize,
+        schedule: &[LayerSpec],
+        hash: LabelingHash,
+    ) -> NSEResult<Self> {
+        let config = Config {
+            k,
+            num_nodes_window,
+            schedule: schedule.into(),
+            hash,
+        };
+        sources::check_config(&config)?;
+        Ok(config)
+    }
+
+    /// Checks that the absolute indices of all nodes of the window fit in 64 bits.
+    pub fn check_window_index(&self, window_index: u64) -> NSEResult<()> {
+        (self.num_nodes_window as u64)
+            .checked_mul(window_index)
+            .and_then(|i| i.checked_add((self.num_nodes_window as u64).saturating_sub(1)))
+            .map(|_| ())
+            .ok_or(NSEError::InvalidWindowIndex(window_index))
+    }
+
+    /// Number of key layers of a window, which is also the number of layers output by sealers.
+    pub fn num_layers(&self) -> usize {
+        self.schedule.len()
+    }
+
+    /// Position of a layer in generation order, starting from 1 for the mask layer.
+    /// This is the layer index the kernels take.
+    pub fn layer_position(&self, index: LayerIndex) -> NSEResult<usize> {
+        if index == LayerIndex::Replica {
+            return Ok(self.num_layers());
+        }
+        (1..=self.num_layers())
+            .find(|&position| self.key_layer(position) == Some(index))
+            .ok_or(NSEError::InvalidLayerIndex(index))
+    }
+
+    /// Key layer at `position` of generation order, `None` if there is no such position.
+    pub fn key_layer(&self, position: usize) -> Option<LayerIndex> {
+        let spec = self.layer_spec(position)?;
+        // Layers are numbered within their kind
+        let n = self.schedule[..position]
+            .iter()
+            .filter(|l| mem::discriminant(*l) == mem::discriminant(&spec))
+            .count();
+        Some(match spec {
+            LayerSpec::Mask => LayerIndex::Mask,
+            LayerSpec::Expander { .. } => LayerIndex::Expander(n),
+            LayerSpec::Butterfly { .. } => LayerIndex::Butterfly(n),
+        })
+    }
+
+    // Kind and degree of the key layer at `position` of generation order
+    pub(crate) fn layer_spec(&self, position: usize) -> Option<LayerSpec> {
+        position
+            .checked_sub(1)
+            .and_then(|i| self.schedule.get(i).cloned())
+    }
+
+    // Distance (Modulo window size) between the parents of a node in the butterfly layer
+    // at `position`, which is the product of degrees of the butterfly layers following it.
+    pub(crate) fn butterfly_factor(&self, position: usize) -> usize {
+        self.schedule[position..]
+            .iter()
+            .fold(1, |factor, layer| match layer {
+                LayerSpec::Butterfly { degree } => {
+                    factor.wrapping_mul(*degree) & (self.num_nodes_window - 1)
+                }
+                _ => factor,
+            })
+    }
+
+    // Largest degree of expander layers, 0 if there are none
+    pub(crate) fn max_degree_expander(&self) -> usize {
+        self.schedule
+            .iter()
+            .map(|layer| match layer {
+                LayerSpec::Expander { degree } => *degree,
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Layer output by sealers at `position` of generation order
+    pub(crate) fn sealer_layer(&self, position: usize) -> Option<LayerIndex> {
+        if position == self.num_layers() {
+            Some(LayerIndex::Replica)
+        } else {
+            self.key_layer(position)
+        }
+    }
 }
 
 pub struct Sealer<'a> {
     original_data: Layer,
     key_generator: KeyGenerator<'a>,
     build_trees: bool,
+    // Next layer, generated while the tree of the current layer was being built
+    prefetched: Option<(LayerIndex, NSEResult<Layer>)>,
 }
 
 impl<'a> Sealer<'a> {
@@ -197,18 +561,102 @@ impl<'a> Sealer<'a> {
     ) -> NSEResult<Self> {
         Ok(Self {
             original_data: input.original_data,
-            key_generator: KeyGenerator::new(config, input.replica_id, input.window_index, gpu)?,
+            key_generator: KeyGenerator::new(&config, input.replica_id, input.window_index, gpu)?,
             build_trees,
+            prefetched: None,
         })
     }
 
-    pub fn seek(&mut self, target_layer_index: usize, target_layer_data: &Layer) -> NSEResult<()> {
+    /// Continue sealing after the provided layer, which should have been output by a sealer
+    /// of the same window.
+    pub fn seek(
+        &mut self,
+        target_layer_index: LayerIndex,
+        target_layer_data: &Layer,
+    ) -> NSEResult<()> {
+        // The last key layer is never output, as it is combined into the replica layer
+        let config = self.key_generator.config();
+        let position = config.layer_position(target_layer_index)?;
+        if target_layer_index != LayerIndex::Replica && position == config.num_layers() {
+            return Err(NSEError::InvalidLayerIndex(target_layer_index));
+        }
         self.key_generator
-            .seek(target_layer_index, target_layer_data)
+            .seek_position(position, target_layer_data)?;
+        self.prefetched = None;
+        Ok(())
+    }
+
+    // Generate the next key layer, or the replica layer if all key layers are generated,
+    // into `output`.
+    fn generate_next_into(&mut self, output: &mut [Node]) -> Option<NSEResult<()>> {
+        let next_key_layer = self.key_generator.next_into(output)?;
+        Some(if self.key_generator.layers_remaining() == 0 {
+            let original_data = &self.original_data;
+            let gpu = &mut self.key_generator.gpu;
+            next_key_layer
+                .and_then(|_| gpu.combine_segment_into(0, &original_data.0, false, output))
+        } else {
+            next_key_layer
+        })
+    }
+
+    fn generate_next(&mut self) -> Option<(LayerIndex, NSEResult<Layer>)> {
+        if self.key_generator.layers_remaining() == 0 {
+            return None;
+        }
+        let index = self
+            .key_generator
+            .config()
+            .sealer_layer(self.key_generator.current_layer_index + 1)?;
+        let mut l = Layer(vec![Node::default(); self.key_generator.gpu.leaf_count()]);
+        let generated = self.generate_next_into(&mut l.0)?;
+        Some((index, generated.map(|_| l)))
+    }
+
+    /// Seals original bytes read from `reader` and writes the replica bytes to `writer`,
+    /// without holding the whole layer in memory (So the `original_data` of the input is
+    /// not used, and may be empty). The key layers which were not output yet are generated
+    /// first, without their trees, and nothing is left to output afterwards.
+    pub fn encode_stream<R: Read, W: Write>(&mut self, reader: R, writer: W) -> NSEResult<usize> {
+        self.prefetched = None;
+        self.key_generator.combine_stream(0, reader, writer, false)
+    }
+
+    /// Same as `next`, but writes the next layer into `output` (E.g. a slice of a
+    /// memory-mapped file) instead of allocating it, and only returns its tree.
+    ///
+    /// When trees are built concurrently, layers are still generated into owned buffers
+    /// (Which are handed to the tree builder while the next layer is generated), and
+    /// copied into `output` once their tree is built.
+    pub fn next_into(&mut self, output: &mut [Node]) -> Option<NSEResult<Vec<Node>>> {
+        if self.prefetched.is_none() && self.key_generator.layers_remaining() == 0 {
+            return None;
+        }
+        let leaf_count = self.key_generator.gpu.leaf_count();
+        if output.len() != leaf_count {
+            return Some(Err(NSEError::OutputLengthMismatch {
+                expected: leaf_count,
+                actual: output.len(),
+            }));
+        }
+        if self.build_trees && self.key_generator.gpu.builds_trees_concurrently() {
+            return Some(self.next()?.map(|layer| {
+                output.copy_from_slice(&layer.base.0);
+                layer.tree
+            }));
+        }
+        let generated = self.generate_next_into(output)?;
+        Some(generated.and_then(|_| {
+            if self.build_trees {
+                self.key_generator.gpu.build_tree(output)
+            } else {
+                Ok(Vec::new())
+            }
+        }))
     }
 
     pub fn new_from_layer(
-        provided_layer_index: usize,
+        provided_layer_index: LayerIndex,
         provided_layer: &Layer,
         config: Config,
         input: SealerInput,
@@ -226,29 +674,32 @@ impl<'a> Iterator for Sealer<'a> {
 
     /// Returns successive layers, starting with mask layer, and ending with sealed replica layer.
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(next_key_layer) = self.key_generator.next() {
-            Some(|| -> NSEResult<LayerOutput> {
-                let layer = if self.key_generator.layers_remaining() == 0 {
-                    self.key_generator.combine_layer(&self.original_data, false)
-                } else {
-                    next_key_layer
-                }?;
-                if self.build_trees {
-                    let tree_builder = self.key_generator.gpu.tree_builder().as_mut().unwrap(); // WARN: unwrap()
-                    let frs = Node::as_frs(layer.0.as_slice());
-                    let (_, fr_tree) = tree_builder.add_final_leaves(frs)?;
-                    let tree = Node::from_frs(&fr_tree).to_vec();
-                    Ok(LayerOutput { base: layer, tree })
-                } else {
-                    Ok(LayerOutput {
-                        base: layer,
-                        tree: Vec::new(), // Maybe change Vec<Node> to Option<Vec<Node>> and return None?
-                    })
+        let (index, next_layer) = match self.prefetched.take() {
+            Some(prefetched) => prefetched,
+            None => self.generate_next()?,
+        };
+        Some(|| -> NSEResult<LayerOutput> {
+            let layer = next_layer?;
+            if self.build_trees {
+                self.key_generator.gpu.submit_tree(layer)?;
+                // When trees are built on another device, start generating the next layer meanwhile.
+                if self.key_generator.gpu.builds_trees_concurrently() {
+                    self.prefetched = self.generate_next();
                 }
-            }())
-        } else {
-            None
-        }
+                let (layer, tree) = self.key_generator.gpu.collect_tree()?;
+                Ok(LayerOutput {
+                    index,
+                    base: layer,
+                    tree,
+                })
+            } else {
+                Ok(LayerOutput {
+                    index,
+                    base: layer,
+                    tree: Vec::new(), // Maybe change Vec<Node> to Option<Vec<Node>> and return None?
+                })
+            }
+        }())
     }
 }
 
@@ -258,8 +709,109 @@ impl<'a> ExactSizeIterator for Sealer<'a> {
     }
 }
 
+/// Seals several windows at once, generating each layer of all windows in a single
+/// kernel launch, which keeps the GPU busy when windows are small.
+///
+/// This is a separate type rather than a mode of `Sealer`, as it yields the layers of all
+/// windows at once, and they are held in the batch buffers of `GPU` (Released when the
+/// `BatchSealer` is dropped) rather than in its current layer, which `Sealer::seek` and
+/// the key cache work on.
+pub struct BatchSealer<'a> {
+    original_data: Vec<Layer>,
+    gpu: &'a mut GPU,
+    current_layer_index: usize,
+    build_trees: bool,
+}
+
+impl<'a> BatchSealer<'a> {
+    pub fn new(
+        config: Config,
+        inputs: Vec<SealerInput>,
+        gpu: &'a mut GPU,
+        build_trees: bool,
+    ) -> NSEResult<Self> {
+        assert_eq!(config.num_nodes_window, gpu.leaf_count());
+        let windows = inputs
+            .iter()
+            .map(|input| KeySpec {
+                replica_id: input.replica_id,
+                window_index: input.window_index,
+            })
+            .collect::<Vec<_>>();
+        gpu.start_batch(&windows)?;
+        Ok(Self {
+            original_data: inputs.into_iter().map(|i| i.original_data).collect(),
+            gpu,
+            current_layer_index: 0,
+            build_trees,
+        })
+    }
+
+    // Generate the next key layers, or the replica layers if all key layers are generated.
+    fn generate_next(&mut self) -> NSEResult<Vec<Layer>> {
+        let position = self.current_layer_index;
+        let layers = match self.gpu.config.key_layer(position) {
+            Some(LayerIndex::Mask) => self.gpu.generate_mask_layers(),
+            Some(LayerIndex::Expander(_)) => self.gpu.generate_expander_layers(position),
+            Some(LayerIndex::Butterfly(_)) => self.gpu.generate_butterfly_layers(position),
+            Some(LayerIndex::Replica) | None => unreachable!(),
+        }?;
+        if position < self.len() {
+            return Ok(layers);
+        }
+        self.gpu.combine_batch_layers(&self.original_data, false)
+    }
+}
+
+impl<'a> Iterator for BatchSealer<'a> {
+    type Item = NSEResult<Vec<LayerOutput>>;
+
+    /// Returns successive layers of all windows, starting with mask layers, and ending
+    /// with sealed replica layers.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current_layer_index >= self.len() {
+            return None;
+        }
+        self.current_layer_index += 1;
+        let index = self.gpu.config.sealer_layer(self.current_layer_index)?;
+        Some(self.generate_next().and_then(|layers| {
+            if !self.build_trees {
+                return Ok(layers
+                    .into_iter()
+                    .map(|base| LayerOutput {
+                        index,
+                        base,
+                        tree: Vec::new(),
+                    })
+                    .collect());
+            }
+            let count = layers.len();
+            for layer in layers.into_iter() {
+                self.gpu.submit_tree(layer)?;
+            }
+            (0..count)
+                .map(|_| {
+                    let (base, tree) = self.gpu.collect_tree()?;
+                    Ok(LayerOutput { index, base, tree })
+                })
+                .collect()
+        }))
+    }
+}
+
+impl<'a> Drop for BatchSealer<'a> {
+    fn drop(&mut self) {
+        self.gpu.end_batch();
+    }
+}
+
+impl<'a> ExactSizeIterator for BatchSealer<'a> {
+    fn len(&self) -> usize {
+        self.gpu.config.num_layers()
+    }
+}
+
 pub struct Unsealer<'a> {
-    #[allow(dead_code)]
     key_generator: KeyGenerator<'a>,
 }
 
@@ -267,45 +819,174 @@ impl<'a> Unsealer<'a> {
     pub fn new(
         config: Config,
         replica_id: ReplicaId,
-        window_index: usize,
+        window_index: u64,
         gpu: &'a mut GPU,
     ) -> NSEResult<Self> {
         Ok(Self {
-            key_generator: KeyGenerator::new(config, replica_id, window_index, gpu)?,
+            key_generator: KeyGenerator::new(&config, replica_id, window_index, gpu)?,
         })
     }
 
-    #[allow(dead_code)]
-    fn unseal_range(&mut self, offset: usize, sealed_data: &[Node]) -> NSEResult<Vec<Node>> {
-        while let Some(layer) = self.key_generator.next() {
-            layer?;
-        }
-
+    pub fn unseal_range(&mut self, offset: usize, sealed_data: &[Node]) -> NSEResult<Vec<Node>> {
+        self.key_generator.generate_key()?;
         self.key_generator
             .combine_segment(offset, sealed_data, true)
     }
 
-    #[allow(dead_code)]
-    fn unseal_layer(&mut self, sealed: Layer) -> NSEResult<Layer> {
+    pub fn unseal_layer(&mut self, sealed: Layer) -> NSEResult<Layer> {
         Ok(Layer(self.unseal_range(0, &sealed.0)?))
     }
+
+    /// Unseals sealed bytes read from `reader`, starting from node `offset` of the window,
+    /// and writes the original bytes to `writer`, without holding the whole layer in memory.
+    pub fn decode_stream<R: Read, W: Write>(
+        &mut self,
+        offset: usize,
+        reader: R,
+        writer: W,
+    ) -> NSEResult<usize> {
+        self.key_generator
+            .combine_stream(offset, reader, writer, true)
+    }
+}
+
+/// Patches a sealed replica layer when a range of its original data changes, using
+/// the additive encoding (`sealed - old + new`), instead of sealing it again.
+pub struct Updater<'a> {
+    key_generator: KeyGenerator<'a>,
+    build_trees: bool,
+}
+
+impl<'a> Updater<'a> {
+    pub fn new(
+        config: Config,
+        replica_id: ReplicaId,
+        window_index: u64,
+        gpu: &'a mut GPU,
+        build_trees: bool,
+    ) -> NSEResult<Self> {
+        Ok(Self {
+            key_generator: KeyGenerator::new(&config, replica_id, window_index, gpu)?,
+            build_trees,
+        })
+    }
+
+    /// Returns the sealed nodes of `new_data`, to replace the sealed nodes of `old_data`
+    /// starting from node `offset`. `sealed_data` (The current sealed nodes of the range)
+    /// is checked to match `old_data`.
+    pub fn update_range(
+        &mut self,
+        offset: usize,
+        sealed_data: &[Node],
+        old_data: &[Node],
+        new_data: &[Node],
+    ) -> NSEResult<Vec<Node>> {
+        if sealed_data.len() != old_data.len() || old_data.len() != new_data.len() {
+            return Err(NSEError::InvalidUpdate(format!(
+                "Sealed, old and new data should have the same length, got {}, {} and {}!",
+                sealed_data.len(),
+                old_data.len(),
+                new_data.len()
+            )));
+        }
+        self.key_generator.generate_key()?;
+        let unsealed = self
+            .key_generator
+            .combine_segment(offset, sealed_data, true)?;
+        if let Some(i) = unsealed.iter().zip(old_data).position(|(u, o)| u != o) {
+            return Err(NSEError::InvalidUpdate(format!(
+                "Sealed node {} does not match the old data!",
+                offset + i
+            )));
+        }
+        self.key_generator.combine_segment(offset, new_data, false)
+    }
+
+    /// Updates the range of `replica` starting from node `offset`, and returns the
+    /// updated replica layer along with its tree (If trees are built).
+    pub fn update_layer(
+        &mut self,
+        replica: &Layer,
+        offset: usize,
+        old_data: &[Node],
+        new_data: &[Node],
+    ) -> NSEResult<LayerOutput> {
+        let end = offset
+            .checked_add(old_data.len())
+            .filter(|&end| end <= replica.0.len())
+            .ok_or(NSEError::SegmentOutOfRange {
+                offset,
+                len: old_data.len(),
+            })?;
+        let updated = self.update_range(offset, &replica.0[offset..end], old_data, new_data)?;
+        let mut layer = replica.clone();
+        layer.0[offset..end].copy_from_slice(&updated);
+        let (layer, tree) = if self.build_trees {
+            self.key_generator.gpu.submit_tree(layer)?;
+            self.key_generator.gpu.collect_tree()?
+        } else {
+            (layer, Vec::new())
+        };
+        Ok(LayerOutput {
+            index: LayerIndex::Replica,
+            base: layer,
+            tree,
+        })
+    }
+}
+
+/// Re-encodes sealed data from one key to another on the device, so that the
+/// original data is never exposed to the host.
+pub struct Reencoder<'a> {
+    from_key: Buffer<Node>,
+    key_generator: KeyGenerator<'a>,
+}
+
+impl<'a> Reencoder<'a> {
+    pub fn new(config: Config, from: KeySpec, to: KeySpec, gpu: &'a mut GPU) -> NSEResult<Self> {
+        let mut from_generator =
+            KeyGenerator::new(&config, from.replica_id, from.window_index, gpu)?;
+        from_generator.generate_key()?;
+        let from_key = from_generator.gpu.copy_current_layer()?;
+        Ok(Self {
+            from_key,
+            key_generator: KeyGenerator::new(
+                &config,
+                to.replica_id,
+                to.window_index,
+                from_generator.gpu,
+            )?,
+        })
+    }
+
+    pub fn reencode_range(&mut self, offset: usize, sealed_data: &[Node]) -> NSEResult<Vec<Node>> {
+        self.key_generator.generate_key()?;
+        self.key_generator
+            .gpu
+            .recombine_segment(&self.from_key, offset, sealed_data)
+    }
+
+    pub fn reencode_layer(&mut self, sealed: &Layer) -> NSEResult<Layer> {
+        Ok(Layer(self.reencode_range(0, &sealed.0)?))
+    }
 }
 
 pub struct KeyGenerator<'a> {
     replica_id: ReplicaId,
-    window_index: usize,
-    current_layer_index: usize,
+    window_index: u64,
+    current_layer_index: usize, // Position of the last generated layer in generation order
     gpu: &'a mut GPU,
 }
 
 impl<'a> KeyGenerator<'a> {
     fn new(
-        config: Config,
+        config: &Config,
         replica_id: ReplicaId,
-        window_index: usize,
+        window_index: u64,
         gpu: &'a mut GPU,
     ) -> NSEResult<Self> {
         assert_eq!(config.num_nodes_window, gpu.leaf_count());
+        config.check_window_index(window_index)?;
         Ok(Self {
             replica_id,
             window_index,
@@ -313,13 +994,53 @@ impl<'a> KeyGenerator<'a> {
             gpu,
         })
     }
-    pub fn seek(&mut self, target_layer_index: usize, target_layer_data: &Layer) -> NSEResult<()> {
-        self.current_layer_index = target_layer_index + 1;
-        self.gpu.push_layer(&target_layer_data)
+    pub fn seek(
+        &mut self,
+        target_layer_index: LayerIndex,
+        target_layer_data: &Layer,
+    ) -> NSEResult<()> {
+        // Only key layers can be sought, the replica layer is not part of the key
+        if target_layer_index == LayerIndex::Replica {
+            return Err(NSEError::InvalidLayerIndex(target_layer_index));
+        }
+        let position = self.config().layer_position(target_layer_index)?;
+        self.seek_position(position, target_layer_data)
+    }
+
+    // Make `target_layer_data` the current layer, at `position` of generation order
+    fn seek_position(&mut self, position: usize, target_layer_data: &Layer) -> NSEResult<()> {
+        self.gpu.push_layer(&target_layer_data)?;
+        self.current_layer_index = position;
+        Ok(())
+    }
+
+    fn config(&self) -> &Config {
+        &self.gpu.config
+    }
+
+    fn key_id(&self) -> KeyId {
+        KeyId {
+            replica_id: self.replica_id,
+            window_index: self.window_index,
+            config: self.config().clone(),
+        }
     }
 
-    fn config(&self) -> Config {
-        self.gpu.config
+    // Generate all remaining layers, leaving the finalized key in the current layer of GPU.
+    // The key is loaded from (And stored in) the key cache of GPU when possible.
+    fn generate_key(&mut self) -> NSEResult<()> {
+        if self.layers_remaining() == 0 {
+            return Ok(());
+        }
+        let key_id = self.key_id();
+        if self.gpu.load_key(&key_id)? {
+            self.current_layer_index = self.len();
+            return Ok(());
+        }
+        while let Some(layer) = self.next() {
+            layer?;
+        }
+        self.gpu.store_key(key_id)
     }
 
     fn layers_remaining(&self) -> usize {
@@ -327,32 +1048,30 @@ impl<'a> KeyGenerator<'a> {
     }
 
     // Generate maske layer on GPU from seeds.
-    fn generate_mask_layer(&mut self) -> NSEResult<Layer> {
+    fn generate_mask_layer(&mut self, output: &mut [Node]) -> NSEResult<()> {
         self.gpu
-            .generate_mask_layer(self.replica_id, self.window_index)
+            .generate_mask_layer_into(self.replica_id, self.window_index, output)
     }
 
     // Generate expander layer on GPU, using previous layer already loaded.
-    fn generate_expander_layer(&mut self) -> NSEResult<Layer> {
-        self.gpu.generate_expander_layer(
+    fn generate_expander_layer(&mut self, output: &mut [Node]) -> NSEResult<()> {
+        self.gpu.generate_expander_layer_into(
             self.replica_id,
             self.window_index,
             self.current_layer_index,
+            output,
         )
     }
     // Generate butterfly layer on GPU, using previous layer already loaded.
-    fn generate_butterfly_layer(&mut self) -> NSEResult<Layer> {
-        self.gpu.generate_butterfly_layer(
+    fn generate_butterfly_layer(&mut self, output: &mut [Node]) -> NSEResult<()> {
+        self.gpu.generate_butterfly_layer_into(
             self.replica_id,
             self.window_index,
             self.current_layer_index,
+            output,
         )
     }
 
-    fn combine_layer(&mut self, layer: &Layer, is_decode: bool) -> NSEResult<Layer> {
-        self.gpu.combine_layer(layer, is_decode)
-    }
-
     fn finalize(&mut self) -> NSEResult<()> {
         self.gpu.finalize()
     }
@@ -366,8 +1085,82 @@ impl<'a> KeyGenerator<'a> {
         self.gpu.combine_segment(offset, segment, is_decode)
     }
 
-    fn last_index(&self) -> usize {
-        self.config().num_expander_layers + self.config().num_butterfly_layers
+    // Reads nodes (In their byte representation) from `reader`, `combine_batch_size` nodes
+    // at a time, combines them with the key of the window (Generated first) starting from
+    // node `offset`, and writes the results to `writer`. Returns the number of nodes processed.
+    //
+    // The stream length is only known once `reader` is exhausted, so if the stream ends
+    // with a partial node (Or contains an invalid one), `PartialNode` (Or `InvalidNode`)
+    // is returned after the results of all the previous batches have been written.
+    fn combine_stream<R: Read, W: Write>(
+        &mut self,
+        offset: usize,
+        mut reader: R,
+        mut writer: W,
+        is_decode: bool,
+    ) -> NSEResult<usize> {
+        self.generate_key()?;
+        let batch_size = self.gpu.combine_batch_size();
+        let mut buffer = vec![0u8; batch_size * NODE_SIZE];
+        let mut segment = Vec::with_capacity(batch_size);
+        let mut combined = vec![Node::default(); batch_size];
+        let mut node_offset = offset;
+        loop {
+            let len = read_chunk(&mut reader, &mut buffer)?;
+            if len % NODE_SIZE != 0 {
+                return Err(NSEError::PartialNode);
+            }
+            let mut temp = [0u8; NODE_SIZE];
+            segment.clear();
+            for slice in buffer[..len].chunks_exact(NODE_SIZE) {
+                temp.copy_from_slice(slice);
+                segment.push(Node::from_bytes(&temp)?);
+            }
+            let combined = &mut combined[..segment.len()];
+            self.gpu
+                .combine_segment_into(node_offset, &segment, is_decode, combined)?;
+            // The read bytes are parsed already, so their buffer is reused for the results
+            for (slice, n) in buffer.chunks_exact_mut(NODE_SIZE).zip(combined.iter()) {
+                slice.copy_from_slice(&n.to_bytes());
+            }
+            writer.write_all(&buffer[..len])?;
+            node_offset += segment.len();
+            if len < buffer.len() {
+                break;
+            }
+        }
+        writer.flush()?;
+        Ok(node_offset - offset)
+    }
+
+    // Generate the next layer into `output`, instead of allocating it.
+    fn next_into(&mut self, output: &mut [Node]) -> Option<NSEResult<()>> {
+        // If current layer is the last, then we have already finished generating layers.
+        if self.current_layer_index >= self.len() {
+            return None;
+        }
+        // Checked before advancing, so that a bad output does not skip a layer
+        if output.len() != self.gpu.leaf_count() {
+            return Some(Err(NSEError::OutputLengthMismatch {
+                expected: self.gpu.leaf_count(),
+                actual: output.len(),
+            }));
+        }
+        self.current_layer_index += 1;
+
+        let generated = match self.config().key_layer(self.current_layer_index) {
+            Some(LayerIndex::Mask) => self.generate_mask_layer(output),
+            Some(LayerIndex::Expander(_)) => self.generate_expander_layer(output),
+            Some(LayerIndex::Butterfly(_)) => self.generate_butterfly_layer(output),
+            Some(LayerIndex::Replica) | None => unreachable!(),
+        };
+        Some(generated.and_then(|_| {
+            // The last layer is the key
+            if self.current_layer_index == self.len() {
+                self.finalize()?;
+            }
+            Ok(())
+        }))
     }
 }
 
@@ -375,43 +1168,17 @@ impl<'a> Iterator for KeyGenerator<'a> {
     type Item = NSEResult<Layer>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let last_index = self.last_index();
-
-        // If current index is last, then we have already finished generating layers.
-        if self.current_layer_index >= last_index {
+        if self.layers_remaining() == 0 {
             return None;
         }
-        self.current_layer_index += 1;
-
-        // First layer is mask layer.
-        if self.current_layer_index == 1 {
-            return Some(self.generate_mask_layer());
-        }
-
-        // When current index equals number of expander layers, we need to generate the last expander layer.
-        // Before that, generate earlier expander layers.
-        if self.current_layer_index <= self.config().num_expander_layers {
-            return Some(self.generate_expander_layer());
-        }
-
-        // When current index equals last index (having been incremented since the first check),
-        // we need to generate the last butterfly layer. Before that, generate earlier butterfly layers.
-        if self.current_layer_index <= last_index {
-            return Some(self.generate_butterfly_layer().and_then(|l| {
-                if self.current_layer_index == last_index {
-                    self.finalize()?;
-                }
-                Ok(l)
-            }));
-        };
-
-        unreachable!();
+        let mut l = Layer(vec![Node::default(); self.gpu.leaf_count()]);
+        Some(self.next_into(&mut l.0)?.map(|_| l))
     }
 }
 
 impl<'a> ExactSizeIterator for KeyGenerator<'a> {
     fn len(&self) -> usize {
-        self.config().num_expander_layers + self.config().num_butterfly_layers
+        self.config().num_layers()
     }
 }
 
@@ -421,15 +1188,10 @@ mod tests {
     use ff::PrimeField;
     use paired::bls12_381::{Fr, FrRepr};
 
-    const TEST_CONFIG: Config = Config {
-        k: 2,
-        num_nodes_window: 512,
-        degree_expander: 96,
-        degree_butterfly: 4,
-        num_expander_layers: 4,
-        num_butterfly_layers: 3,
-    };
-    const TEST_WINDOW_INDEX: usize = 1234567890;
+    fn test_config() -> Config {
+        Config::new(2, 512, 96, 4, 4, 3, LabelingHash::Sha256).unwrap()
+    }
+    const TEST_WINDOW_INDEX: u64 = 1234567890;
     const TEST_REPLICA_ID: ReplicaId = ReplicaId([123u8; 32]);
 
     pub fn incrementing_layer(start: usize, count: usize) -> Layer {
@@ -442,12 +1204,12 @@ mod tests {
 
     #[test]
     fn test_sealer() {
-        let ctx =
-            GPUContext::default(TEST_CONFIG, TreeOptions::Enabled { rows_to_discard: 2 }).unwrap();
-        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
-        let original_data = incrementing_layer(123, TEST_CONFIG.num_nodes_window);
+        let ctx = GPUContext::default(test_config(), TreeOptions::Enabled { rows_to_discard: 2 })
+            .unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let original_data = incrementing_layer(123, test_config().num_nodes_window);
         let sealer = Sealer::new(
-            TEST_CONFIG,
+            test_config(),
             SealerInput {
                 replica_id: TEST_REPLICA_ID,
                 window_index: TEST_WINDOW_INDEX,
@@ -469,6 +1231,13 @@ mod tests {
             .collect::<Vec<_>>();
 
         let layer_to_restart = &layers[layer_index_to_restart].base;
+        assert_eq!(layers[0].index, LayerIndex::Mask);
+        assert_eq!(
+            layers[layer_index_to_restart].index,
+            LayerIndex::Expander(3)
+        );
+        assert_eq!(layers[4].index, LayerIndex::Butterfly(1));
+        assert_eq!(layers[6].index, LayerIndex::Replica);
 
         assert_eq!(
             roots[..7].to_vec(),
@@ -540,9 +1309,9 @@ mod tests {
         );
 
         let mut restarted_sealer = Sealer::new_from_layer(
-            layer_index_to_restart,
+            layers[layer_index_to_restart].index,
             layer_to_restart,
-            TEST_CONFIG,
+            test_config(),
             SealerInput {
                 replica_id: TEST_REPLICA_ID,
                 window_index: TEST_WINDOW_INDEX,
@@ -568,8 +1337,12 @@ mod tests {
 
         let seek_target = 2;
         restarted_sealer
-            .seek(seek_target, &layers[seek_target].base)
+            .seek(layers[seek_target].index, &layers[seek_target].base)
             .unwrap();
+        assert!(restarted_sealer
+            .key_generator
+            .seek(LayerIndex::Replica, &layers[layers.len() - 1].base)
+            .is_err());
 
         let sought_roots = restarted_sealer
             .map(|r| {
@@ -582,20 +1355,187 @@ mod tests {
         assert_eq!(&roots[seek_target + 1..], sought_roots.as_slice());
     }
 
+    #[test]
+    fn test_replica_id_derivation() {
+        // Same as `bytes_into_fr_repr_safe` of storage-proofs' `generate_replica_id`
+        let replica_id = ReplicaId::derive(&[1u8; 32], 42, &[2u8; 32], Node::default(), &[3u8; 32]);
+        let hex = "43b7b485b42d3013bc5ddf9fbe056638f4ea326506362483ac624988fea64d2b";
+        assert_eq!(replica_id.to_string(), hex);
+        assert_eq!(hex.parse::<ReplicaId>().unwrap(), replica_id);
+        assert!("43b7b4".parse::<ReplicaId>().is_err());
+        assert!(
+            ReplicaId::derive(&[1u8; 32], 43, &[2u8; 32], Node::default(), &[3u8; 32])
+                != replica_id
+        );
+        assert!(
+            ReplicaId::derive(&[1u8; 32], 42, &[2u8; 32], Node::default(), &[4u8; 32])
+                != replica_id
+        );
+    }
+
+    #[test]
+    fn test_layer_index() {
+        let config = test_config();
+        for position in 1..=config.num_layers() {
+            let index = config.key_layer(position).unwrap();
+            assert_eq!(config.layer_position(index).unwrap(), position);
+        }
+        assert_eq!(config.key_layer(4), Some(LayerIndex::Expander(3)));
+        assert_eq!(config.key_layer(7), Some(LayerIndex::Butterfly(3)));
+        assert_eq!(config.key_layer(0), None);
+        assert_eq!(config.key_layer(8), None);
+        assert_eq!(config.sealer_layer(7), Some(LayerIndex::Replica));
+        assert_eq!(config.layer_position(LayerIndex::Replica).unwrap(), 7);
+        assert!(config.layer_position(LayerIndex::Expander(0)).is_err());
+        assert!(config.layer_position(LayerIndex::Expander(4)).is_err());
+        assert!(config.layer_position(LayerIndex::Butterfly(4)).is_err());
+    }
+
+    #[test]
+    fn test_layer_schedule() {
+        static DEFAULT_SCHEDULE: [LayerSpec; 7] = [
+            LayerSpec::Mask,
+            LayerSpec::Expander { degree: 96 },
+            LayerSpec::Expander { degree: 96 },
+            LayerSpec::Expander { degree: 96 },
+            LayerSpec::Butterfly { degree: 4 },
+            LayerSpec::Butterfly { degree: 4 },
+            LayerSpec::Butterfly { degree: 4 },
+        ];
+        assert_eq!(test_config().schedule[..], DEFAULT_SCHEDULE[..]);
+        for position in 5..=7 {
+            assert_eq!(
+                test_config().butterfly_factor(position),
+                4usize.pow((7 - position) as u32)
+            );
+        }
+
+        static SCHEDULE: [LayerSpec; 4] = [
+            LayerSpec::Mask,
+            LayerSpec::Butterfly { degree: 8 },
+            LayerSpec::Expander { degree: 64 },
+            LayerSpec::Butterfly { degree: 4 },
+        ];
+        let config = Config::with_schedule(2, 512, &SCHEDULE, LabelingHash::Sha256).unwrap();
+        assert_eq!(config.num_layers(), 4);
+        assert_eq!(config.key_layer(2), Some(LayerIndex::Butterfly(1)));
+        assert_eq!(config.key_layer(3), Some(LayerIndex::Expander(1)));
+        assert_eq!(config.key_layer(4), Some(LayerIndex::Butterfly(2)));
+        assert_eq!(config.butterfly_factor(2), 4);
+        assert_eq!(config.max_degree_expander(), 64);
+        assert!(crate::sources::generate_nse_program(&config).is_ok());
+        let layers = config
+            .schedule
+            .iter()
+            .map(|spec| spec.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(layers, vec!["M", "B8", "E64", "B4"]);
+        for (layer, spec) in layers.iter().zip(config.schedule.iter()) {
+            assert_eq!(layer.parse::<LayerSpec>().unwrap(), *spec);
+        }
+        for layer in ["", "M1", "E", "Ex", "X4"].iter() {
+            assert!(layer.parse::<LayerSpec>().is_err());
+        }
+
+        // Invalid configs are rejected when they are created
+        let invalid = [
+            vec![LayerSpec::Expander { degree: 64 }],
+            vec![LayerSpec::Mask, LayerSpec::Mask],
+            vec![LayerSpec::Mask, LayerSpec::Expander { degree: 63 }],
+            vec![LayerSpec::Mask, LayerSpec::Butterfly { degree: 6 }],
+        ];
+        for schedule in invalid.iter() {
+            assert!(Config::with_schedule(2, 512, schedule, LabelingHash::Sha256).is_err());
+        }
+        assert!(Config::new(3, 512, 96, 4, 4, 3, LabelingHash::Sha256).is_err());
+        assert!(Config::new(2, 0, 96, 4, 4, 3, LabelingHash::Sha256).is_err());
+
+        // And when building the program, as fields may be set directly
+        let config = Config {
+            schedule: invalid[0].clone().into(),
+            ..test_config()
+        };
+        assert!(crate::sources::generate_nse_program(&config).is_err());
+    }
+
+    #[test]
+    fn test_sealer_schedule() {
+        static SCHEDULE: [LayerSpec; 5] = [
+            LayerSpec::Mask,
+            LayerSpec::Butterfly { degree: 8 },
+            LayerSpec::Expander { degree: 64 },
+            LayerSpec::Expander { degree: 32 },
+            LayerSpec::Butterfly { degree: 4 },
+        ];
+        let config = Config::with_schedule(2, 512, &SCHEDULE, LabelingHash::Sha256).unwrap();
+        let original_data = incrementing_layer(123, config.num_nodes_window);
+
+        let ctx = GPUContext::default(config.clone(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, config.clone()).unwrap();
+        let sealer = Sealer::new(
+            config.clone(),
+            SealerInput {
+                replica_id: TEST_REPLICA_ID,
+                window_index: TEST_WINDOW_INDEX,
+                original_data: original_data.clone(),
+            },
+            &mut gpu,
+            false,
+        )
+        .unwrap();
+        let layers = sealer.collect::<NSEResult<Vec<_>>>().unwrap();
+        assert_eq!(
+            layers.iter().map(|l| l.index).collect::<Vec<_>>(),
+            vec![
+                LayerIndex::Mask,
+                LayerIndex::Butterfly(1),
+                LayerIndex::Expander(1),
+                LayerIndex::Expander(2),
+                LayerIndex::Replica
+            ]
+        );
+
+        let mut unsealer =
+            Unsealer::new(config, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+        let sealed_data = layers.last().unwrap().base.clone();
+        assert_eq!(unsealer.unseal_layer(sealed_data).unwrap(), original_data);
+    }
+
+    #[test]
+    fn test_ordinary_layer() {
+        let mut rng = rand::thread_rng();
+        let layer = Layer::random(&mut rng, 16);
+        let ordinary = OrdinaryLayer::from(&layer);
+        assert_eq!(ordinary.0[3].0, layer.0[3].0.into_repr());
+        assert_eq!(Layer::try_from(&ordinary).unwrap(), layer);
+
+        let bytes = Vec::<u8>::from(&ordinary);
+        assert_eq!(
+            &bytes[3 * NODE_SIZE..4 * NODE_SIZE],
+            &layer.0[3].to_bytes()[..]
+        );
+        assert_eq!(OrdinaryLayer::try_from(&bytes[..]).unwrap(), ordinary);
+        assert!(OrdinaryLayer::try_from(&bytes[1..]).is_err());
+
+        let invalid = OrdinaryLayer(vec![OrdinaryNode(FrRepr([u64::max_value(); 4]))]);
+        assert!(Layer::try_from(&invalid).is_err());
+    }
+
     #[test]
     fn test_sealer_unsealer_consistency() {
         use rand::thread_rng;
 
         let mut rng = thread_rng();
-        let original_data = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
+        let original_data = Layer::random(&mut rng, test_config().num_nodes_window);
         let replica_id = ReplicaId::random(&mut rng);
-        let window_index: usize = rng.gen();
+        let window_index =
+            rng.gen_range(0, u64::max_value() / test_config().num_nodes_window as u64);
 
-        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
-        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
 
         let sealer = Sealer::new(
-            TEST_CONFIG,
+            test_config(),
             SealerInput {
                 replica_id,
                 window_index,
@@ -608,7 +1548,8 @@ mod tests {
 
         let sealed_data = sealer.last().unwrap().unwrap().base;
 
-        let mut unsealer = Unsealer::new(TEST_CONFIG, replica_id, window_index, &mut gpu).unwrap();
+        let mut unsealer =
+            Unsealer::new(test_config(), replica_id, window_index, &mut gpu).unwrap();
 
         let unsealed_data = unsealer.unseal_layer(sealed_data.clone()).unwrap();
 
@@ -627,4 +1568,360 @@ mod tests {
             offset = end;
         })
     }
+
+    #[test]
+    fn test_stream_unsealing() {
+        use rand::thread_rng;
+
+        let mut rng = thread_rng();
+        let original_data = Layer::random(&mut rng, test_config().num_nodes_window);
+        let replica_id = ReplicaId::random(&mut rng);
+
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        gpu.set_combine_batch_size(100).unwrap();
+
+        let sealer = Sealer::new(
+            test_config(),
+            SealerInput {
+                replica_id,
+                window_index: TEST_WINDOW_INDEX,
+                original_data: original_data.clone(),
+            },
+            &mut gpu,
+            false,
+        )
+        .unwrap();
+        let sealed_data =
+            Vec::<u8>::from(&OrdinaryLayer::from(&sealer.last().unwrap().unwrap().base));
+        let original_data = Vec::<u8>::from(&OrdinaryLayer::from(&original_data));
+
+        let mut unsealer =
+            Unsealer::new(test_config(), replica_id, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+
+        let mut unsealed_data = Vec::new();
+        let count = unsealer
+            .decode_stream(0, &sealed_data[..], &mut unsealed_data)
+            .unwrap();
+        assert_eq!(count, test_config().num_nodes_window);
+        assert_eq!(unsealed_data, original_data);
+
+        let (start, end) = (123 * NODE_SIZE, 345 * NODE_SIZE);
+        let mut unsealed_range = Vec::new();
+        unsealer
+            .decode_stream(123, &sealed_data[start..end], &mut unsealed_range)
+            .unwrap();
+        assert_eq!(unsealed_range, &original_data[start..end]);
+
+        assert!(unsealer
+            .decode_stream(0, &sealed_data[..NODE_SIZE + 1], &mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        use rand::thread_rng;
+
+        let mut rng = thread_rng();
+        let original_data = Layer::random(&mut rng, test_config().num_nodes_window);
+        let original_bytes = Vec::<u8>::from(&OrdinaryLayer::from(&original_data));
+        let replica_id = ReplicaId::random(&mut rng);
+        let input = |original_data| SealerInput {
+            replica_id,
+            window_index: TEST_WINDOW_INDEX,
+            original_data,
+        };
+
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        gpu.set_combine_batch_size(100).unwrap();
+
+        let sealer = Sealer::new(test_config(), input(original_data), &mut gpu, false).unwrap();
+        let sealed_bytes =
+            Vec::<u8>::from(&OrdinaryLayer::from(&sealer.last().unwrap().unwrap().base));
+
+        // The key is generated by the sealer, whatever the GPU held before
+        gpu.generate_mask_layer(ReplicaId::random(&mut rng), TEST_WINDOW_INDEX)
+            .unwrap();
+        let mut sealer =
+            Sealer::new(test_config(), input(Layer(Vec::new())), &mut gpu, false).unwrap();
+        let mut encoded = Vec::new();
+        let count = sealer
+            .encode_stream(&original_bytes[..], &mut encoded)
+            .unwrap();
+        assert_eq!(count, test_config().num_nodes_window);
+        assert_eq!(encoded, sealed_bytes);
+        assert!(sealer.next().is_none());
+
+        gpu.generate_mask_layer(ReplicaId::random(&mut rng), TEST_WINDOW_INDEX)
+            .unwrap();
+        let mut unsealer =
+            Unsealer::new(test_config(), replica_id, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+        let mut decoded = Vec::new();
+        let count = unsealer
+            .decode_stream(0, &encoded[..], &mut decoded)
+            .unwrap();
+        assert_eq!(count, test_config().num_nodes_window);
+        assert_eq!(decoded, original_bytes);
+
+        // Batches preceding a partial node are written before the error is detected
+        let mut sealer =
+            Sealer::new(test_config(), input(Layer(Vec::new())), &mut gpu, false).unwrap();
+        let mut partial = Vec::new();
+        assert!(sealer
+            .encode_stream(&original_bytes[..150 * NODE_SIZE + 1], &mut partial)
+            .is_err());
+        assert_eq!(partial, &encoded[..100 * NODE_SIZE]);
+    }
+
+    #[test]
+    fn test_key_cache() {
+        use rand::thread_rng;
+
+        let mut rng = thread_rng();
+        let original_data = Layer::random(&mut rng, test_config().num_nodes_window);
+        let replica_id = ReplicaId::random(&mut rng);
+        let key_id = KeyId {
+            replica_id,
+            window_index: TEST_WINDOW_INDEX,
+            config: test_config(),
+        };
+
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+
+        let sealer = Sealer::new(
+            test_config(),
+            SealerInput {
+                replica_id,
+                window_index: TEST_WINDOW_INDEX,
+                original_data: original_data.clone(),
+            },
+            &mut gpu,
+            false,
+        )
+        .unwrap();
+        let sealed_data = sealer.last().unwrap().unwrap().base;
+
+        for &(device_capacity, host_capacity) in [(1, 0), (0, 1), (2, 2)].iter() {
+            gpu.set_key_cache_capacity(0, 0);
+            gpu.set_key_cache_capacity(device_capacity, host_capacity);
+
+            let mut unsealer =
+                Unsealer::new(test_config(), replica_id, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+            assert_eq!(
+                unsealer.unseal_layer(sealed_data.clone()).unwrap(),
+                original_data
+            );
+            assert!(gpu.key_cache().contains(&key_id));
+
+            // Clobber the current layer, so that only a cached key can give correct results
+            gpu.push_layer(&original_data).unwrap();
+
+            let mut unsealer =
+                Unsealer::new(test_config(), replica_id, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+            assert_eq!(
+                unsealer.unseal_layer(sealed_data.clone()).unwrap(),
+                original_data
+            );
+        }
+    }
+
+    #[test]
+    fn test_reencoder() {
+        use rand::thread_rng;
+
+        let mut rng = thread_rng();
+        let original_data = Layer::random(&mut rng, test_config().num_nodes_window);
+        let from = KeySpec {
+            replica_id: ReplicaId::random(&mut rng),
+            window_index: TEST_WINDOW_INDEX,
+        };
+        let to = KeySpec {
+            replica_id: ReplicaId::random(&mut rng),
+            window_index: TEST_WINDOW_INDEX + 1,
+        };
+
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+
+        let mut seal = |key: KeySpec| {
+            Sealer::new(
+                test_config(),
+                SealerInput {
+                    replica_id: key.replica_id,
+                    window_index: key.window_index,
+                    original_data: original_data.clone(),
+                },
+                &mut gpu,
+                false,
+            )
+            .unwrap()
+            .last()
+            .unwrap()
+            .unwrap()
+            .base
+        };
+        let from_sealed = seal(from);
+        let to_sealed = seal(to);
+
+        let mut reencoder = Reencoder::new(test_config(), from, to, &mut gpu).unwrap();
+        assert_eq!(reencoder.reencode_layer(&from_sealed).unwrap(), to_sealed);
+        assert_eq!(
+            reencoder
+                .reencode_range(100, &from_sealed.0[100..200])
+                .unwrap(),
+            &to_sealed.0[100..200]
+        );
+    }
+
+    #[test]
+    fn test_updater() {
+        use rand::thread_rng;
+
+        let mut rng = thread_rng();
+        let replica_id = ReplicaId::random(&mut rng);
+        let original_data = Layer::random(&mut rng, test_config().num_nodes_window);
+        let new_range = Layer::random(&mut rng, 100).0;
+        let mut updated_data = original_data.clone();
+        updated_data.0[200..300].copy_from_slice(&new_range);
+
+        let ctx = GPUContext::default(test_config(), TreeOptions::Enabled { rows_to_discard: 2 })
+            .unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+
+        let mut seal = |data: &Layer| {
+            Sealer::new(
+                test_config(),
+                SealerInput {
+                    replica_id,
+                    window_index: TEST_WINDOW_INDEX,
+                    original_data: data.clone(),
+                },
+                &mut gpu,
+                true,
+            )
+            .unwrap()
+            .last()
+            .unwrap()
+            .unwrap()
+        };
+        let replica = seal(&original_data);
+        let updated_replica = seal(&updated_data);
+
+        let mut updater =
+            Updater::new(test_config(), replica_id, TEST_WINDOW_INDEX, &mut gpu, true).unwrap();
+        let output = updater
+            .update_layer(&replica.base, 200, &original_data.0[200..300], &new_range)
+            .unwrap();
+        assert_eq!(output, updated_replica);
+
+        // Old data not matching the replica is rejected
+        assert!(updater
+            .update_range(200, &replica.base.0[200..300], &new_range, &new_range)
+            .is_err());
+        assert!(updater
+            .update_layer(&replica.base, 500, &new_range, &new_range)
+            .is_err());
+    }
+
+    #[test]
+    fn test_batch_sealer() {
+        use rand::thread_rng;
+
+        let mut rng = thread_rng();
+        let inputs = (0..3)
+            .map(|i| SealerInput {
+                replica_id: ReplicaId::random(&mut rng),
+                window_index: TEST_WINDOW_INDEX + i,
+                original_data: Layer::random(&mut rng, test_config().num_nodes_window),
+            })
+            .collect::<Vec<_>>();
+
+        let ctx = GPUContext::default(test_config(), TreeOptions::Enabled { rows_to_discard: 2 })
+            .unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+
+        let expected = inputs
+            .iter()
+            .map(|input| {
+                Sealer::new(test_config(), input.clone(), &mut gpu, true)
+                    .unwrap()
+                    .collect::<NSEResult<Vec<_>>>()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        // Batched expander layers are generated in tiles of bit-streams
+        gpu.set_stream_tile_size(128).unwrap();
+        let batch_sealer = BatchSealer::new(test_config(), inputs, &mut gpu, true).unwrap();
+        assert_eq!(batch_sealer.len(), expected[0].len());
+        for (layer_index, outputs) in batch_sealer.enumerate() {
+            let outputs = outputs.unwrap();
+            assert_eq!(outputs.len(), expected.len());
+            for (output, window) in outputs.iter().zip(expected.iter()) {
+                assert_eq!(output, &window[layer_index]);
+            }
+        }
+        assert_eq!(gpu.batch_len(), 0);
+    }
+
+    #[test]
+    fn test_sealer_into() {
+        use rand::thread_rng;
+
+        let mut rng = thread_rng();
+        let input = SealerInput {
+            replica_id: ReplicaId::random(&mut rng),
+            window_index: TEST_WINDOW_INDEX,
+            original_data: Layer::random(&mut rng, test_config().num_nodes_window),
+        };
+
+        let ctx = GPUContext::default(test_config(), TreeOptions::Enabled { rows_to_discard: 2 })
+            .unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let expected = Sealer::new(test_config(), input.clone(), &mut gpu, true)
+            .unwrap()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+
+        // All layers are written into a single caller-provided buffer
+        let mut sealer = Sealer::new(test_config(), input.clone(), &mut gpu, true).unwrap();
+        let mut buffer = vec![Node::default(); expected.len() * test_config().num_nodes_window];
+        for (output, expected) in buffer
+            .chunks_mut(test_config().num_nodes_window)
+            .zip(expected.iter())
+        {
+            let tree = sealer.next_into(output).unwrap().unwrap();
+            assert_eq!(output, &expected.base.0[..]);
+            assert_eq!(tree, expected.tree);
+        }
+        assert!(sealer.next_into(&mut buffer[..]).is_none());
+
+        let mut sealer = Sealer::new(test_config(), input.clone(), &mut gpu, true).unwrap();
+        assert!(sealer.next_into(&mut buffer[..10]).unwrap().is_err());
+        // A bad output does not skip the layer
+        let output = &mut buffer[..test_config().num_nodes_window];
+        let tree = sealer.next_into(output).unwrap().unwrap();
+        assert_eq!(output, &expected[0].base.0[..]);
+        assert_eq!(tree, expected[0].tree);
+
+        // Trees built on another thread overlap with the generation of the next layer
+        let ctx = GPUContext::default(
+            test_config(),
+            TreeOptions::EnabledCPU { rows_to_discard: 2 },
+        )
+        .unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let mut sealer = Sealer::new(test_config(), input, &mut gpu, true).unwrap();
+        for (output, expected) in buffer
+            .chunks_mut(test_config().num_nodes_window)
+            .zip(expected.iter())
+        {
+            let tree = sealer.next_into(output).unwrap().unwrap();
+            assert_eq!(output, &expected.base.0[..]);
+            assert_eq!(tree, expected.tree);
+        }
+        assert!(sealer.next_into(&mut buffer[..]).is_none());
+    }
 }
@@ -0,0 +1,238 @@
+use crate::{Layer, NSEError, NSEResult, Node};
+use generic_array::typenum::U8;
+use neptune::poseidon::{Poseidon, PoseidonConstants};
+use paired::bls12_381::Bls12;
+
+/// Arity of the layer trees (Same as the `TreeBuilder`s).
+pub const TREE_ARITY: usize = 8;
+
+pub(crate) fn tree_constants() -> PoseidonConstants<Bls12, U8> {
+    PoseidonConstants::<Bls12, U8>::new()
+}
+
+// Hash `TREE_ARITY` children into their parent
+pub(crate) fn hash_children(constants: &PoseidonConstants<Bls12, U8>, children: &[Node]) -> Node {
+    assert_eq!(children.len(), TREE_ARITY);
+    Node(Poseidon::new_with_preimage(Node::as_frs(children), constants).hash())
+}
+
+// Hash a row of a tree into the row above it
+pub(crate) fn hash_row(constants: &PoseidonConstants<Bls12, U8>, row: &[Node]) -> Vec<Node> {
+    row.chunks(TREE_ARITY)
+        .map(|children| hash_children(constants, children))
+        .collect()
+}
+
+// Number of rows above the leaves (I.e. `log_8(leaf_count)`)
+pub(crate) fn tree_height(leaf_count: usize) -> NSEResult<usize> {
+    let mut height = 0;
+    let mut width = leaf_count;
+    while width > 1 {
+        if width % TREE_ARITY != 0 {
+            return Err(NSEError::InvalidTree(format!(
+                "Leaf count {} is not a power of {}!",
+                leaf_count, TREE_ARITY
+            )));
+        }
+        width /= TREE_ARITY;
+        height += 1;
+    }
+    Ok(height)
+}
+
+/// Proof that a node is the `index`th leaf of a tree. The root is not part of the
+/// proof, as it should come from a trusted source (E.g. a commitment).
+#[derive(PartialEq, Debug, Clone)]
+pub struct InclusionProof {
+    pub leaf: Node,
+    pub index: usize,
+    /// `TREE_ARITY - 1` siblings per row, from the leaves up to the root.
+    pub siblings: Vec<Vec<Node>>,
+}
+
+impl InclusionProof {
+    /// Position of the path node among its siblings, per row.
+    pub fn path(&self) -> Vec<usize> {
+        let mut index = self.index;
+        self.siblings
+            .iter()
+            .map(|_| {
+                let pos = index % TREE_ARITY;
+                index /= TREE_ARITY;
+                pos
+            })
+            .collect()
+    }
+
+    /// Checks the proof against the trusted `root` of a tree with `leaf_count` leaves.
+    pub fn verify(&self, root: Node, leaf_count: usize) -> bool {
+        let height = match tree_height(leaf_count) {
+            Ok(height) => height,
+            Err(_) => return false,
+        };
+        if self.siblings.len() != height || self.index >= leaf_count {
+            return false;
+        }
+        let constants = tree_constants();
+        let mut current = self.leaf;
+        for (siblings, pos) in self.siblings.iter().zip(self.path()) {
+            if siblings.len() != TREE_ARITY - 1 {
+                return false;
+            }
+            let mut children = siblings.clone();
+            children.insert(pos, current);
+            current = hash_children(&constants, &children);
+        }
+        current == root
+    }
+}
+
+/// Generates inclusion proofs of a layer, given the tree returned along with it
+/// (I.e. `LayerOutput.tree`). Rows discarded by the `TreeBuilder` are
+/// reconstructed from the layer, only for the subtree containing the proven leaf.
+pub struct TreeProver<'a> {
+    layer: &'a Layer,
+    tree: &'a [Node],
+    rows_to_discard: usize,
+    height: usize,
+    constants: PoseidonConstants<Bls12, U8>,
+}
+
+impl<'a> TreeProver<'a> {
+    pub fn new(layer: &'a Layer, tree: &'a [Node], rows_to_discard: usize) -> NSEResult<Self> {
+        let leaf_count = layer.0.len();
+        let height = tree_height(leaf_count)?;
+        if rows_to_discard >= height {
+            return Err(NSEError::InvalidTree(format!(
+                "Cannot discard {} rows of a tree with height {}!",
+                rows_to_discard, height
+            )));
+        }
+        let expected_len = (rows_to_discard + 1..=height)
+            .map(|row| leaf_count / TREE_ARITY.pow(row as u32))
+            .sum::<usize>();
+        if tree.len() != expected_len {
+            return Err(NSEError::InvalidTree(format!(
+                "Expected {} tree nodes, got {}!",
+                expected_len,
+                tree.len()
+            )));
+        }
+        Ok(Self {
+            layer,
+            tree,
+            rows_to_discard,
+            height,
+            constants: tree_constants(),
+        })
+    }
+
+    pub fn root(&self) -> Node {
+        self.tree[self.tree.len() - 1]
+    }
+
+    // Returns `row`th row of the tree (`row > rows_to_discard`)
+    fn stored_row(&self, row: usize) -> &[Node] {
+        let leaf_count = self.layer.0.len();
+        let offset = (self.rows_to_discard + 1..row)
+            .map(|r| leaf_count / TREE_ARITY.pow(r as u32))
+            .sum::<usize>();
+        &self.tree[offset..offset + leaf_count / TREE_ARITY.pow(row as u32)]
+    }
+
+    pub fn prove(&self, index: usize) -> NSEResult<InclusionProof> {
+        if index >= self.layer.0.len() {
+            return Err(NSEError::NodeIndexOutOfRange(index));
+        }
+
+        let mut siblings = Vec::with_capacity(self.height);
+        let mut push_siblings = |row: &[Node], index: usize| {
+            let start = index - index % TREE_ARITY;
+            let mut group = row[start..start + TREE_ARITY].to_vec();
+            group.remove(index % TREE_ARITY);
+            siblings.push(group);
+        };
+
+        // Rebuild the subtree whose root is in the lowest stored row, which
+        // contains all the discarded nodes needed for the proof.
+        let subtree_row = self.rows_to_discard + 1;
+        let subtree_width = TREE_ARITY.pow(subtree_row as u32);
+        let subtree_index = index / subtree_width;
+        let mut row = self.layer.0[subtree_index * subtree_width..][..subtree_width].to_vec();
+        let mut local_index = index % subtree_width;
+        for _ in 0..subtree_row {
+            push_siblings(&row, local_index);
+            row = hash_row(&self.constants, &row);
+            local_index /= TREE_ARITY;
+        }
+        if row[0] != self.stored_row(subtree_row)[subtree_index] {
+            return Err(NSEError::InvalidTree(
+                "Layer does not match the tree!".into(),
+            ));
+        }
+
+        let mut row_index = subtree_index;
+        for r in subtree_row..self.height {
+            push_siblings(self.stored_row(r), row_index);
+            row_index /= TREE_ARITY;
+        }
+
+        Ok(InclusionProof {
+            leaf: self.layer.0[index],
+            index,
+            siblings,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::{build_tree, new_tree_builder};
+    use neptune::batch_hasher::BatcherType;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_inclusion_proofs() {
+        let mut rng = thread_rng();
+        let leaf_count = 512;
+        let layer = Layer::random(&mut rng, leaf_count);
+
+        for rows_to_discard in 0..3 {
+            let mut tree_builder =
+                new_tree_builder(BatcherType::CPU, leaf_count, rows_to_discard).unwrap();
+            let tree = build_tree(&mut tree_builder, &layer.0).unwrap();
+            let prover = TreeProver::new(&layer, &tree, rows_to_discard).unwrap();
+            let root = prover.root();
+
+            for &index in [0, 1, 7, 8, 100, 511].iter() {
+                let proof = prover.prove(index).unwrap();
+                assert_eq!(proof.siblings.len(), 3);
+                assert!(proof.verify(root, leaf_count));
+
+                // A proof only verifies against the root it was generated for
+                assert!(!proof.verify(Node::random(&mut rng), leaf_count));
+
+                let mut bad_proof = proof.clone();
+                bad_proof.leaf = Node::random(&mut rng);
+                assert!(!bad_proof.verify(root, leaf_count));
+
+                let mut bad_proof = proof.clone();
+                bad_proof.index = (index + 1) % leaf_count;
+                assert!(!bad_proof.verify(root, leaf_count));
+
+                // Indices beyond the tree would alias other leaves through `path`
+                let mut bad_proof = proof.clone();
+                bad_proof.index = index + leaf_count;
+                assert!(!bad_proof.verify(root, leaf_count));
+
+                let mut bad_proof = proof.clone();
+                bad_proof.siblings.pop();
+                assert!(!bad_proof.verify(root, leaf_count));
+                assert!(!proof.verify(root, leaf_count * TREE_ARITY));
+            }
+
+            assert!(prover.prove(leaf_count).is_err());
+        }
+    }
+}
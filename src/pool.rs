@@ -1,5 +1,9 @@
+use crate::tree::TreeBackend;
 use crate::NarrowStackedExpander;
-use crate::{Config, GPUContext, LayerOutput, NSEResult, Sealer, SealerInput, TreeOptions, GPU};
+use crate::{
+    Config, GPUContext, LayerOutput, NSEError, NSEResult, Sealer, SealerInput, SharedProgram,
+    TreeOptions, GPU,
+};
 use log::*;
 use ocl::Device;
 use std::sync::mpsc;
@@ -7,10 +11,12 @@ use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
 
+type SealRequest = (SealerInput, mpsc::Sender<NSEResult<LayerOutput>>);
+
 struct SealerWorker {
     died: bool,
     busy: Arc<Mutex<bool>>,
-    channel: mpsc::Sender<(SealerInput, mpsc::Sender<NSEResult<LayerOutput>>)>,
+    channel: mpsc::Sender<SealRequest>,
 }
 
 pub struct SealerPool {
@@ -19,74 +25,139 @@ pub struct SealerPool {
     workers: Vec<SealerWorker>,
 }
 
+// Serve sealing requests of a worker, on its own command queue of the device.
+// `trees` is the tree backend shared by the workers of the device.
+#[allow(clippy::too_many_arguments)]
+fn serve(
+    name: String,
+    program: &SharedProgram,
+    config: Config,
+    tree_options: TreeOptions,
+    trees: Option<TreeBackend>,
+    requests: mpsc::Receiver<SealRequest>,
+    busy: Arc<Mutex<bool>>,
+    cond: Arc<Condvar>,
+) {
+    let tree_enabled = tree_options.is_enabled();
+    match GPUContext::from_shared_with_trees(program, tree_options, trees)
+        .and_then(|ctx| GPU::new(ctx, config.clone()))
+    {
+        Ok(mut gpu) => {
+            info!("{}: GPU context initialized, waiting for inputs...", name);
+
+            for (inp, sender) in requests.into_iter() {
+                info!("{}: New sealing request!", name);
+                let mut busy = busy.lock().unwrap();
+                match Sealer::new(config.clone(), inp, &mut gpu, tree_enabled) {
+                    Ok(sealer) => {
+                        for output in sealer {
+                            // If receiving channel is dead
+                            if sender.send(output).is_err() {
+                                error!("{}: Requester died!", name);
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("{}: Cannot create sealer! Error: {}", name, e);
+                    }
+                }
+                *busy = false;
+                drop(busy);
+                cond.notify_all(); // Notify that one GPU is not busy anymore
+                info!("{}: Sealing finished, waiting for inputs...", name);
+            }
+        }
+        Err(e) => {
+            error!("{}: Cannot create GPU context! Error: {}", name, e);
+        }
+    }
+    warn!("{}: Worker died.", name);
+    cond.notify_all(); // Notify when worker dies
+}
+
 impl SealerPool {
     pub fn new(devices: Vec<Device>, config: Config, tree_options: TreeOptions) -> NSEResult<Self> {
-        info!("Creating a sealer pool of {} devices.", devices.len());
+        Self::new_with_lanes(devices, config, tree_options, 1)
+    }
+
+    /// Creates a pool running `lanes` seals concurrently on each device, each on its
+    /// own command queue, in order to hide kernel launch and transfer latencies.
+    ///
+    /// Lanes of a device share its compiled program (And OpenCL context), and a single
+    /// tree builder running on its own thread, so a lane only adds a command queue and
+    /// the buffers of its layers. This is equivalent to a single `GPU` keeping a layer
+    /// state per queue, while reusing the `Sealer` unchanged.
+    pub fn new_with_lanes(
+        devices: Vec<Device>,
+        config: Config,
+        tree_options: TreeOptions,
+        lanes: usize,
+    ) -> NSEResult<Self> {
+        if lanes == 0 {
+            return Err(NSEError::InvalidLaneCount);
+        }
+        info!(
+            "Creating a sealer pool of {} devices, {} lanes each.",
+            devices.len(),
+            lanes
+        );
 
         let mut workers = Vec::new();
         let cond = Arc::new(Condvar::new());
 
-        let tree_enabled = if let TreeOptions::Enabled { rows_to_discard: _ } = tree_options {
-            true
-        } else {
-            false
-        };
-
         for (i, dev) in devices.into_iter().enumerate() {
-            info!("Creating Sealer-Worker on device[{}]: {}", i, dev.name()?);
-
-            let (fn_tx, fn_rx): (
-                mpsc::Sender<(SealerInput, mpsc::Sender<NSEResult<LayerOutput>>)>,
-                mpsc::Receiver<(SealerInput, mpsc::Sender<NSEResult<LayerOutput>>)>,
-            ) = mpsc::channel();
-
-            let busy = Arc::new(Mutex::new(false));
-            workers.push(SealerWorker {
-                channel: fn_tx,
-                busy: Arc::clone(&busy),
-                died: false,
-            });
+            info!("Creating Sealer-Workers on device[{}]: {}", i, dev.name()?);
+
+            let mut lane_inputs = Vec::new();
+            for lane in 0..lanes {
+                let (fn_tx, fn_rx) = mpsc::channel::<SealRequest>();
+                let busy = Arc::new(Mutex::new(false));
+                workers.push(SealerWorker {
+                    channel: fn_tx,
+                    busy: Arc::clone(&busy),
+                    died: false,
+                });
+                lane_inputs.push((lane, fn_rx, busy));
+            }
 
             let cond = Arc::clone(&cond);
+            let config = config.clone();
             thread::spawn(move || {
-                match GPUContext::new(dev, config.clone(), tree_options.clone())
-                    .and_then(|ctx| GPU::new(ctx, config.clone()))
-                {
-                    Ok(mut gpu) => {
-                        info!(
-                            "Device[{}]: GPU context initialized, waiting for inputs...",
-                            i
-                        );
-
-                        for (inp, sender) in fn_rx.into_iter() {
-                            info!("Device[{}]: New sealing request!", i);
-                            let mut busy = busy.lock().unwrap();
-                            match Sealer::new(config.clone(), inp, &mut gpu, tree_enabled) {
-                                Ok(sealer) => {
-                                    for output in sealer {
-                                        // If receiving channel is dead
-                                        if sender.send(output).is_err() {
-                                            error!("Device[{}]: Requester died!", i);
-                                            break;
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("Device[{}]: Cannot create sealer! Error: {}", i, e);
-                                }
-                            }
-                            *busy = false;
-                            drop(busy);
-                            cond.notify_all(); // Notify that one GPU is not busy anymore
-                            info!("Device[{}]: Sealing finished, waiting for inputs...", i);
+                let setup = SharedProgram::new(dev, config.clone()).and_then(|program| {
+                    let trees =
+                        TreeBackend::new_shareable(tree_options, dev, config.num_nodes_window)?;
+                    Ok((program, trees))
+                });
+                match setup {
+                    Ok((program, trees)) => {
+                        for (lane, fn_rx, busy) in lane_inputs {
+                            let program = program.clone();
+                            let trees = trees.as_ref().and_then(|t| t.share());
+                            let cond = Arc::clone(&cond);
+                            let config = config.clone();
+                            let name = format!("Device[{}][{}]", i, lane);
+                            thread::spawn(move || {
+                                serve(
+                                    name,
+                                    &program,
+                                    config,
+                                    tree_options,
+                                    trees,
+                                    fn_rx,
+                                    busy,
+                                    cond,
+                                )
+                            });
                         }
                     }
                     Err(e) => {
-                        error!("Device[{}]: Cannot create GPU context! Error: {}", i, e);
+                        // Dropping the request receivers marks the workers as dead
+                        error!("Device[{}]: Cannot initialize! Error: {}", i, e);
+                        drop(lane_inputs);
+                        cond.notify_all();
                     }
                 }
-                warn!("Device[{}]: Worker died.", i);
-                cond.notify_all(); // Notify when worker dies
             });
         }
         Ok(SealerPool {
@@ -146,33 +217,29 @@ mod tests {
     use crate::*;
     use rand::{thread_rng, Rng};
 
-    const TEST_CONFIG: Config = Config {
-        k: 2,
-        num_nodes_window: 512,
-        degree_expander: 96,
-        degree_butterfly: 4,
-        num_expander_layers: 4,
-        num_butterfly_layers: 3,
-    };
+    fn test_config() -> Config {
+        Config::new(2, 512, 96, 4, 4, 3, LabelingHash::Sha256).unwrap()
+    }
 
-    #[test]
-    fn test_sealer_pool() {
+    fn check_sealer_pool(lanes: usize) {
         const NUM_RUNS: usize = 10;
         let mut rng = thread_rng();
 
         let inputs: Vec<SealerInput> = (0..NUM_RUNS)
             .map(|_| SealerInput {
                 replica_id: ReplicaId::random(&mut rng),
-                window_index: rng.gen(),
-                original_data: Layer::random(&mut rng, TEST_CONFIG.num_nodes_window),
+                window_index: rng
+                    .gen_range(0, u64::max_value() / test_config().num_nodes_window as u64),
+                original_data: Layer::random(&mut rng, test_config().num_nodes_window),
             })
             .collect();
 
         let pool_outputs = {
-            let mut pool = SealerPool::new(
+            let mut pool = SealerPool::new_with_lanes(
                 utils::all_devices().unwrap(),
-                TEST_CONFIG,
+                test_config(),
                 TreeOptions::Enabled { rows_to_discard: 2 },
+                lanes,
             )
             .unwrap();
             let pool_output_channels = inputs
@@ -185,13 +252,13 @@ mod tests {
                 .collect::<Vec<_>>()
         };
 
-        let ctx =
-            GPUContext::default(TEST_CONFIG, TreeOptions::Enabled { rows_to_discard: 2 }).unwrap();
-        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let ctx = GPUContext::default(test_config(), TreeOptions::Enabled { rows_to_discard: 2 })
+            .unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
         let normal_outputs = inputs
             .iter()
             .map(|inp| {
-                Sealer::new(TEST_CONFIG, inp.clone(), &mut gpu, true)
+                Sealer::new(test_config(), inp.clone(), &mut gpu, true)
                     .unwrap()
                     .map(|o| o.unwrap())
                     .collect::<Vec<_>>()
@@ -200,4 +267,18 @@ mod tests {
 
         assert_eq!(pool_outputs, normal_outputs);
     }
+
+    #[test]
+    fn test_sealer_pool() {
+        check_sealer_pool(1);
+    }
+
+    #[test]
+    fn test_sealer_pool_lanes() {
+        check_sealer_pool(3);
+        assert!(
+            SealerPool::new_with_lanes(Vec::new(), test_config(), TreeOptions::Disabled, 0)
+                .is_err()
+        );
+    }
 }
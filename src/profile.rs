@@ -0,0 +1,102 @@
+use crate::{GPUError, NSEResult};
+use ocl::enums::{ProfilingInfo, ProfilingInfoResult};
+use ocl::Event;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+// Events are resolved into timings once this many are pending
+const MAX_PENDING_EVENTS: usize = 1024;
+
+/// Device time spent on an operation (A kernel, or a buffer transfer).
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct OperationTiming {
+    pub count: usize,
+    pub total: Duration,
+}
+
+impl OperationTiming {
+    pub fn average(&self) -> Duration {
+        if self.count == 0 {
+            Duration::default()
+        } else {
+            self.total / self.count as u32
+        }
+    }
+}
+
+/// Timings of operations enqueued since profiling was enabled (Or last reset), per
+/// kernel name, `write_buffer`, `read_buffer` and `copy_buffer`.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct ProfileReport {
+    pub operations: BTreeMap<String, OperationTiming>,
+}
+
+impl ProfileReport {
+    pub fn total(&self) -> Duration {
+        self.operations.values().map(|t| t.total).sum()
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, timing) in self.operations.iter() {
+            writeln!(
+                f,
+                "{}: {} calls, {:?} total, {:?} average",
+                name,
+                timing.count,
+                timing.total,
+                timing.average()
+            )?;
+        }
+        write!(f, "Total: {:?}", self.total())
+    }
+}
+
+fn event_time(event: &Event, info: ProfilingInfo) -> NSEResult<u64> {
+    match event.profiling_info(info).map_err(ocl::Error::from)? {
+        ProfilingInfoResult::Start(t) | ProfilingInfoResult::End(t) => Ok(t),
+        _ => Err(GPUError::Other("Unexpected profiling info!".into()).into()),
+    }
+}
+
+// Collects the events of profiled operations
+#[derive(Default)]
+pub(crate) struct Profiler {
+    pending: Vec<(String, Event)>,
+    report: ProfileReport,
+}
+
+impl Profiler {
+    pub(crate) fn record(&mut self, name: &str, event: Event) -> NSEResult<()> {
+        self.pending.push((name.to_string(), event));
+        if self.pending.len() >= MAX_PENDING_EVENTS {
+            self.resolve()?;
+        }
+        Ok(())
+    }
+
+    // Wait for pending events and add their timings to the report
+    fn resolve(&mut self) -> NSEResult<()> {
+        for (name, event) in self.pending.drain(..) {
+            event.wait_for().map_err(ocl::Error::from)?;
+            let start = event_time(&event, ProfilingInfo::Start)?;
+            let end = event_time(&event, ProfilingInfo::End)?;
+            let timing = self.report.operations.entry(name).or_default();
+            timing.count += 1;
+            timing.total += Duration::from_nanos(end.saturating_sub(start));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn report(&mut self) -> NSEResult<ProfileReport> {
+        self.resolve()?;
+        Ok(self.report.clone())
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.pending.clear();
+        self.report = ProfileReport::default();
+    }
+}
@@ -1,8 +1,11 @@
-use super::Config;
+use super::{Config, LabelingHash, LayerSpec, NSEError, NSEResult};
 use itertools::join;
 use paired::bls12_381::Fr;
 
 static SHA256_SRC: &str = include_str!("cl/hash/sha256.cl");
+static BLAKE2S_SRC: &str = include_str!("cl/hash/blake2s.cl");
+static LABEL_SHA256_SRC: &str = include_str!("cl/label/sha256.cl");
+static LABEL_BLAKE2S_SRC: &str = include_str!("cl/label/blake2s.cl");
 static COMMON_SRC: &str = include_str!("cl/common.cl");
 static MASK_SRC: &str = include_str!("cl/mask.cl");
 static EXPANDER_SRC: &str = include_str!("cl/expander.cl");
@@ -11,46 +14,84 @@ static COMBINE_SRC: &str = include_str!("cl/combine.cl");
 
 static SHA256_BITS: usize = 256;
 
-fn config(conf: Config) -> String {
-    assert!(conf.num_nodes_window > conf.k as usize);
-    assert!(conf.num_nodes_window.count_ones() == 1);
-    assert!(conf.k.count_ones() == 1);
-    let bit_size = (conf.num_nodes_window as f64 / conf.k as f64).log2() as usize;
-    assert!(bit_size % 8 == 0);
-    assert!(conf.degree_butterfly.count_ones() == 1);
-    let stream_hash_count =
-        ((conf.degree_expander * bit_size) as f64 / SHA256_BITS as f64).ceil() as usize;
-
-    format!(
+pub(crate) fn bit_size(conf: &Config) -> usize {
+    (conf.num_nodes_window as f64 / conf.k as f64).log2() as usize
+}
+
+/// Number of SHA-256 hashes in the bit-stream of each node, used by the expander
+/// layers (Long enough for the largest degree, and at least one hash).
+pub(crate) fn stream_hash_count(conf: &Config) -> usize {
+    let bits = conf.max_degree_expander() * bit_size(conf);
+    std::cmp::max((bits as f64 / SHA256_BITS as f64).ceil() as usize, 1)
+}
+
+fn invalid(message: &str) -> NSEResult<()> {
+    Err(NSEError::InvalidConfig(message.to_string()))
+}
+
+pub(crate) fn check_config(conf: &Config) -> NSEResult<()> {
+    if conf.k.count_ones() != 1 {
+        return invalid("k should be a power of 2!");
+    }
+    if conf.num_nodes_window.count_ones() != 1 || conf.num_nodes_window <= conf.k as usize {
+        return invalid("Window size should be a power of 2, larger than k!");
+    }
+    if bit_size(conf) % 8 != 0 {
+        return invalid("Parent indices should be whole bytes!");
+    }
+    if conf.schedule.first() != Some(&LayerSpec::Mask) {
+        return invalid("The first layer should be a mask layer!");
+    }
+    for layer in conf.schedule[1..].iter() {
+        match layer {
+            LayerSpec::Mask => return invalid("Only the first layer can be a mask layer!"),
+            LayerSpec::Expander { degree } if *degree == 0 || degree % 2 != 0 => {
+                return invalid("Expander degrees should be even and positive!")
+            }
+            LayerSpec::Butterfly { degree } if *degree < 2 || degree.count_ones() != 1 => {
+                return invalid("Butterfly degrees should be powers of 2, at least 2!")
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn config(conf: &Config) -> NSEResult<String> {
+    check_config(conf)?;
+    let bit_size = bit_size(conf);
+    let stream_hash_count = stream_hash_count(conf);
+
+    // Degrees of layers are passed to kernels as arguments, as they may differ per layer
+    Ok(format!(
         "#define N ({})
          #define K ({})
          #define LOG2_K ({})
-         #define DEGREE_EXPANDER ({})
-         #define DEGREE_BUTTERFLY ({})
-         #define LOG2_DEGREE_BUTTERFLY ({})
-         #define NUM_EXPANDER_LAYERS ({})
-         #define NUM_BUTTERFLY_LAYERS ({})
          #define BIT_SIZE ({})
          #define STREAM_HASH_COUNT ({})\n",
         conf.num_nodes_window,
         conf.k,
         (conf.k as f64).log2() as u32,
-        conf.degree_expander,
-        conf.degree_butterfly,
-        (conf.degree_butterfly as f64).log2() as u32,
-        conf.num_expander_layers,
-        conf.num_butterfly_layers,
         bit_size,
         stream_hash_count,
-    )
+    ))
+}
+
+// Sources implementing the `label_*` interface used by layer kernels
+fn labeling_hash(hash: LabelingHash) -> String {
+    match hash {
+        LabelingHash::Sha256 => LABEL_SHA256_SRC.to_string(),
+        LabelingHash::Blake2s => join(&[BLAKE2S_SRC, LABEL_BLAKE2S_SRC], "\n"),
+    }
 }
 
-pub fn generate_nse_program(conf: Config) -> String {
-    join(
+pub fn generate_nse_program(conf: &Config) -> NSEResult<String> {
+    Ok(join(
         &[
-            config(conf),
+            config(conf)?,
             ff_cl_gen::field::<Fr>("Fr"),
-            SHA256_SRC.to_string(),
+            SHA256_SRC.to_string(), // Always needed, for parents of expander layers
+            labeling_hash(conf.hash),
             COMMON_SRC.to_string(),
             MASK_SRC.to_string(),
             EXPANDER_SRC.to_string(),
@@ -58,5 +99,5 @@ pub fn generate_nse_program(conf: Config) -> String {
             COMBINE_SRC.to_string(),
         ],
         "\n",
-    )
+    ))
 }
@@ -0,0 +1,396 @@
+use crate::{utils, GPUError, Layer, NSEError, NSEResult, Node, OrdinaryLayer, TreeOptions, GPU};
+use generic_array::typenum::U8;
+use log::{error, info};
+use neptune::batch_hasher::BatcherType;
+use neptune::cl::GPUSelector;
+use neptune::tree_builder::{TreeBuilder, TreeBuilderTrait};
+use ocl::Device;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+const TREE_BUILDER_BATCH_SIZE: usize = 400_000;
+
+pub(crate) fn new_tree_builder(
+    batcher: BatcherType,
+    leaf_count: usize,
+    rows_to_discard: usize,
+) -> NSEResult<TreeBuilder<U8>> {
+    Ok(TreeBuilder::<U8>::new(
+        Some(batcher),
+        leaf_count,
+        TREE_BUILDER_BATCH_SIZE,
+        rows_to_discard,
+    )?)
+}
+
+pub(crate) fn build_tree(
+    tree_builder: &mut TreeBuilder<U8>,
+    layer: &[Node],
+) -> NSEResult<Vec<Node>> {
+    let frs = Node::as_frs(layer);
+    let (_, fr_tree) = tree_builder.add_final_leaves(frs)?;
+    Ok(Node::from_frs(&fr_tree).to_vec())
+}
+
+type TreeResult = (Layer, NSEResult<Vec<Node>>);
+
+// Builds trees on a dedicated thread, so that the labeling device is free to
+// generate the next layer meanwhile. The `TreeBuilder` is created inside the thread.
+// Layers are moved to the thread, and handed back along with their trees. A worker
+// can be shared (E.g. by the lanes of a `SealerPool`), each handle receiving its own
+// trees in submission order, through a channel per submitted layer.
+pub(crate) struct TreeWorker {
+    layers: mpsc::Sender<(Layer, mpsc::Sender<TreeResult>)>,
+    trees: VecDeque<mpsc::Receiver<TreeResult>>,
+}
+
+impl TreeWorker {
+    fn new(batcher: BatcherType, leaf_count: usize, rows_to_discard: usize) -> NSEResult<Self> {
+        let (layer_tx, layer_rx) = mpsc::channel::<(Layer, mpsc::Sender<TreeResult>)>();
+        let (init_tx, init_rx) = mpsc::channel::<NSEResult<()>>();
+
+        thread::spawn(move || {
+            let mut tree_builder = match new_tree_builder(batcher, leaf_count, rows_to_discard) {
+                Ok(tree_builder) => {
+                    init_tx.send(Ok(())).ok();
+                    tree_builder
+                }
+                Err(e) => {
+                    error!("Cannot create tree builder! Error: {}", e);
+                    init_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+
+            // Runs until every handle is dropped. Trees of dropped handles are discarded.
+            for (layer, tree_tx) in layer_rx.into_iter() {
+                let tree = build_tree(&mut tree_builder, &layer.0);
+                tree_tx.send((layer, tree)).ok();
+            }
+        });
+
+        init_rx.recv().map_err(|_| NSEError::TreeWorkerDied)??;
+
+        Ok(TreeWorker::with_layers(layer_tx))
+    }
+
+    fn with_layers(layers: mpsc::Sender<(Layer, mpsc::Sender<TreeResult>)>) -> Self {
+        TreeWorker {
+            layers,
+            trees: VecDeque::new(),
+        }
+    }
+
+    // New handle of the same worker thread
+    fn share(&self) -> Self {
+        TreeWorker::with_layers(self.layers.clone())
+    }
+}
+
+pub(crate) enum TreeBackend {
+    // Trees are built synchronously, on submission
+    Local {
+        tree_builder: TreeBuilder<U8>,
+        pending: VecDeque<TreeResult>,
+    },
+    Worker(TreeWorker),
+}
+
+impl TreeBackend {
+    pub(crate) fn new(
+        tree_options: TreeOptions,
+        device: Device,
+        leaf_count: usize,
+    ) -> NSEResult<Option<Self>> {
+        Ok(match tree_options {
+            TreeOptions::Enabled { rows_to_discard } => Some(TreeBackend::Local {
+                tree_builder: new_tree_builder(
+                    BatcherType::CustomGPU(GPUSelector::BusId(utils::get_bus_id(device)?)),
+                    leaf_count,
+                    rows_to_discard,
+                )?,
+                pending: VecDeque::new(),
+            }),
+            TreeOptions::EnabledCPU { rows_to_discard } => Some(TreeBackend::Worker(
+                TreeWorker::new(BatcherType::CPU, leaf_count, rows_to_discard)?,
+            )),
+            TreeOptions::EnabledOnDevice {
+                rows_to_discard,
+                device,
+            } => Some(TreeBackend::Worker(TreeWorker::new(
+                BatcherType::CustomGPU(GPUSelector::BusId(utils::get_bus_id(device)?)),
+                leaf_count,
+                rows_to_discard,
+            )?)),
+            TreeOptions::Disabled => None,
+        })
+    }
+
+    // Builds trees on CPU synchronously, without any OpenCL device or context
+    pub(crate) fn new_cpu(leaf_count: usize, rows_to_discard: usize) -> NSEResult<Self> {
+        Ok(TreeBackend::Local {
+            tree_builder: new_tree_builder(BatcherType::CPU, leaf_count, rows_to_discard)?,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Same as `new`, but trees are always built on a worker thread, so that the backend
+    /// can be shared (See `share`), even when trees are built on the labeling device.
+    pub(crate) fn new_shareable(
+        tree_options: TreeOptions,
+        device: Device,
+        leaf_count: usize,
+    ) -> NSEResult<Option<Self>> {
+        Ok(match tree_options {
+            TreeOptions::Enabled { rows_to_discard } => Some(TreeBackend::Worker(TreeWorker::new(
+                BatcherType::CustomGPU(GPUSelector::BusId(utils::get_bus_id(device)?)),
+                leaf_count,
+                rows_to_discard,
+            )?)),
+            _ => TreeBackend::new(tree_options, device, leaf_count)?,
+        })
+    }
+
+    // New handle of the same `TreeBuilder`, only worker backends can be shared
+    pub(crate) fn share(&self) -> Option<Self> {
+        match self {
+            TreeBackend::Local { .. } => None,
+            TreeBackend::Worker(worker) => Some(TreeBackend::Worker(worker.share())),
+        }
+    }
+
+    pub(crate) fn is_concurrent(&self) -> bool {
+        match self {
+            TreeBackend::Local { .. } => false,
+            TreeBackend::Worker(_) => true,
+        }
+    }
+
+    // Layers are taken by value, so that they are moved (Not copied) to the worker
+    pub(crate) fn submit(&mut self, layer: Layer) -> NSEResult<()> {
+        match self {
+            TreeBackend::Local {
+                tree_builder,
+                pending,
+            } => {
+                let tree = build_tree(tree_builder, &layer.0);
+                pending.push_back((layer, tree));
+                Ok(())
+            }
+            TreeBackend::Worker(worker) => {
+                let (tree_tx, tree_rx) = mpsc::channel::<TreeResult>();
+                worker
+                    .layers
+                    .send((layer, tree_tx))
+                    .map_err(|_| NSEError::TreeWorkerDied)?;
+                worker.trees.push_back(tree_rx);
+                Ok(())
+            }
+        }
+    }
+
+    // Returns the submitted layer back, along with its tree
+    pub(crate) fn collect(&mut self) -> NSEResult<(Layer, Vec<Node>)> {
+        let (layer, tree) = match self {
+            TreeBackend::Local { pending, .. } => pending
+                .pop_front()
+                .ok_or_else(|| GPUError::Other("No tree has been submitted!".into()))?,
+            TreeBackend::Worker(worker) => worker
+                .trees
+                .pop_front()
+                .ok_or_else(|| GPUError::Other("No tree has been submitted!".into()))?
+                .recv()
+                .map_err(|_| NSEError::TreeWorkerDied)?,
+        };
+        Ok((layer, tree?))
+    }
+
+    // Build the tree of a borrowed layer. Only the worker needs to copy it.
+    pub(crate) fn build(&mut self, layer: &[Node]) -> NSEResult<Vec<Node>> {
+        match self {
+            TreeBackend::Local { tree_builder, .. } => build_tree(tree_builder, layer),
+            TreeBackend::Worker(_) => {
+                self.submit(Layer(layer.to_vec()))?;
+                Ok(self.collect()?.1)
+            }
+        }
+    }
+}
+
+// Tree backend used by a `TreeRebuilder`
+enum RebuilderTrees<'a> {
+    Context(&'a mut GPU),
+    Cpu(TreeBackend),
+}
+
+/// Rebuilds trees of already generated layers, without regenerating them.
+/// Trees are built through the `TreeBuilder` configured in the `GPUContext`
+/// (Or on CPU, see `new_cpu`), so the results are identical to the trees returned
+/// by `Sealer`.
+pub struct TreeRebuilder<'a> {
+    trees: RebuilderTrees<'a>,
+}
+
+impl<'a> TreeRebuilder<'a> {
+    pub fn new(gpu: &'a mut GPU) -> Self {
+        Self {
+            trees: RebuilderTrees::Context(gpu),
+        }
+    }
+
+    /// Rebuilds trees on CPU, without any OpenCL device (E.g. on machines without a GPU).
+    pub fn new_cpu(leaf_count: usize, rows_to_discard: usize) -> NSEResult<TreeRebuilder<'static>> {
+        Ok(TreeRebuilder {
+            trees: RebuilderTrees::Cpu(TreeBackend::new_cpu(leaf_count, rows_to_discard)?),
+        })
+    }
+
+    fn trees(&mut self) -> NSEResult<&mut TreeBackend> {
+        match &mut self.trees {
+            RebuilderTrees::Context(gpu) => gpu.trees(),
+            RebuilderTrees::Cpu(trees) => Ok(trees),
+        }
+    }
+
+    pub fn rebuild_layer(&mut self, layer: &Layer) -> NSEResult<Vec<Node>> {
+        self.trees()?.build(&layer.0)
+    }
+
+    /// Rebuilds the tree of a layer stored as bytes (As produced by
+    /// `Vec::<u8>::from(&OrdinaryLayer)`).
+    pub fn rebuild_layer_file<P: AsRef<Path>>(&mut self, path: P) -> NSEResult<Vec<Node>> {
+        info!("Loading layer from {}...", path.as_ref().display());
+        let data = std::fs::read(path)?;
+        let trees = self.trees()?;
+        let layer = Layer::try_from(&OrdinaryLayer::try_from(&data[..])?)?;
+        trees.submit(layer)?;
+        Ok(trees.collect()?.1)
+    }
+
+    pub fn rebuild_layers(&mut self, layers: &[Layer]) -> NSEResult<Vec<Vec<Node>>> {
+        let trees = self.trees()?;
+        // Submit everything first, so that concurrent tree builders are kept busy.
+        for l in layers.iter() {
+            trees.submit(l.clone())?;
+        }
+        layers.iter().map(|_| Ok(trees.collect()?.1)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use rand::thread_rng;
+
+    fn test_config() -> Config {
+        Config::new(2, 512, 96, 4, 4, 3, LabelingHash::Sha256).unwrap()
+    }
+
+    #[test]
+    fn test_tree_rebuilder() {
+        let mut rng = thread_rng();
+        let ctx = GPUContext::default(test_config(), TreeOptions::Enabled { rows_to_discard: 2 })
+            .unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let sealer = Sealer::new(
+            test_config(),
+            SealerInput {
+                replica_id: ReplicaId::random(&mut rng),
+                window_index: 1234,
+                original_data: Layer::random(&mut rng, test_config().num_nodes_window),
+            },
+            &mut gpu,
+            true,
+        )
+        .unwrap();
+        let outputs = sealer.collect::<NSEResult<Vec<_>>>().unwrap();
+
+        let layers = outputs.iter().map(|o| o.base.clone()).collect::<Vec<_>>();
+        let trees = outputs.into_iter().map(|o| o.tree).collect::<Vec<_>>();
+
+        let mut rebuilder = TreeRebuilder::new(&mut gpu);
+        assert_eq!(rebuilder.rebuild_layers(&layers).unwrap(), trees);
+
+        let dir = std::env::temp_dir().join("nse-tree-rebuilder-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("replica");
+        std::fs::write(
+            &path,
+            Vec::<u8>::from(&OrdinaryLayer::from(&layers[layers.len() - 1])),
+        )
+        .unwrap();
+        assert_eq!(
+            &rebuilder.rebuild_layer_file(&path).unwrap(),
+            &trees[trees.len() - 1]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cpu_tree_builder() {
+        let mut rng = thread_rng();
+        let layers = (0..3)
+            .map(|_| Layer::random(&mut rng, test_config().num_nodes_window))
+            .collect::<Vec<_>>();
+
+        let gpu_trees = {
+            let ctx =
+                GPUContext::default(test_config(), TreeOptions::Enabled { rows_to_discard: 2 })
+                    .unwrap();
+            let mut gpu = GPU::new(ctx, test_config()).unwrap();
+            TreeRebuilder::new(&mut gpu)
+                .rebuild_layers(&layers)
+                .unwrap()
+        };
+
+        let cpu_trees = {
+            let ctx = GPUContext::default(
+                test_config(),
+                TreeOptions::EnabledCPU { rows_to_discard: 2 },
+            )
+            .unwrap();
+            let mut gpu = GPU::new(ctx, test_config()).unwrap();
+            TreeRebuilder::new(&mut gpu)
+                .rebuild_layers(&layers)
+                .unwrap()
+        };
+
+        assert_eq!(gpu_trees, cpu_trees);
+
+        // No GPU context is needed on CPU
+        let mut rebuilder = TreeRebuilder::new_cpu(test_config().num_nodes_window, 2).unwrap();
+        assert_eq!(rebuilder.rebuild_layers(&layers).unwrap(), cpu_trees);
+        assert_eq!(rebuilder.rebuild_layer(&layers[1]).unwrap(), cpu_trees[1]);
+    }
+
+    #[test]
+    fn test_tree_device() {
+        let mut rng = thread_rng();
+        let input = SealerInput {
+            replica_id: ReplicaId::random(&mut rng),
+            window_index: 1234,
+            original_data: Layer::random(&mut rng, test_config().num_nodes_window),
+        };
+
+        let seal = |tree_options: TreeOptions| {
+            let ctx = GPUContext::default(test_config(), tree_options).unwrap();
+            let mut gpu = GPU::new(ctx, test_config()).unwrap();
+            Sealer::new(test_config(), input.clone(), &mut gpu, true)
+                .unwrap()
+                .collect::<NSEResult<Vec<_>>>()
+                .unwrap()
+        };
+
+        let outputs = seal(TreeOptions::Enabled { rows_to_discard: 2 });
+        let device_outputs = seal(TreeOptions::EnabledOnDevice {
+            rows_to_discard: 2,
+            device: *utils::all_devices().unwrap().last().unwrap(),
+        });
+
+        assert_eq!(outputs, device_outputs);
+    }
+}
@@ -0,0 +1,346 @@
+//! Tuning of the local work sizes of layer kernels.
+//!
+//! The best local work size of a kernel depends on the device, and on the config
+//! (Which determines the private memory footprint of the kernels), so tuned sizes
+//! are persisted per device name and config.
+
+use crate::{
+    Config, GPUContext, GPUError, KeySpec, LabelingHash, Layer, LayerIndex, NSEError, NSEResult,
+    NarrowStackedExpander, Node, ReplicaId, TreeOptions, GPU,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Kernels tuned by `Autotuner`. Combine kernels are not tuned, as they are mostly
+/// launched on segments of arbitrary length, which the driver chooses local work sizes of.
+pub const TUNED_KERNELS: [&str; 9] = [
+    "generate_mask",
+    "generate_expander",
+    "generate_butterfly",
+    "generate_mask_batch",
+    "generate_expander_batch",
+    "generate_butterfly_batch",
+    "generate_streams",
+    "generate_montgomery",
+    "generate_ordinary",
+];
+
+const CANDIDATE_SIZES: [usize; 6] = [32, 64, 128, 256, 512, 1024];
+
+// Number of windows of the batch the batched kernels are tuned with
+const TUNING_BATCH_LEN: usize = 4;
+
+/// Local work sizes, per kernel name.
+pub type LocalWorkSizes = HashMap<String, usize>;
+
+/// Tuned local work sizes, per device name and config. Persisted as lines of
+/// tab-separated device name, config, kernel name and local work size.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct TuningTable {
+    entries: HashMap<(String, String), LocalWorkSizes>,
+}
+
+// Serializes the fields of a config which kernels depend on (E.g.
+// `k=2;nodes=512;hash=sha256;layers=M,E96,B4`), independently of its `Debug` format
+fn config_key(config: &Config) -> String {
+    let hash = match config.hash {
+        LabelingHash::Sha256 => "sha256",
+        LabelingHash::Blake2s => "blake2s",
+    };
+    let layers = config
+        .schedule
+        .iter()
+        .map(|spec| spec.to_string())
+        .collect::<Vec<_>>();
+    format!(
+        "k={};nodes={};hash={};layers={}",
+        config.k,
+        config.num_nodes_window,
+        hash,
+        layers.join(",")
+    )
+}
+
+// Key of the entries of a device and config
+fn entry_key(gpu: &GPU) -> NSEResult<(String, String)> {
+    Ok((gpu.context().device().name()?, config_key(&gpu.config)))
+}
+
+impl TuningTable {
+    /// Loads a tuning table, an empty table is returned if the file does not exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> NSEResult<Self> {
+        let mut table = TuningTable::default();
+        if !path.as_ref().exists() {
+            return Ok(table);
+        }
+        for line in fs::read_to_string(path)?.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parts = line.split('\t').collect::<Vec<_>>();
+            if parts.len() != 4 {
+                return Err(NSEError::InvalidTuningFile(format!(
+                    "Expected 4 columns: {}",
+                    line
+                )));
+            }
+            let size = parts[3]
+                .parse::<usize>()
+                .ok()
+                .filter(|&size| size > 0)
+                .ok_or_else(|| {
+                    NSEError::InvalidTuningFile(format!("Invalid local work size: {}", parts[3]))
+                })?;
+            table
+                .entries
+                .entry((parts[0].to_string(), parts[1].to_string()))
+                .or_default()
+                .insert(parts[2].to_string(), size);
+        }
+        Ok(table)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> NSEResult<()> {
+        let mut lines = self
+            .entries
+            .iter()
+            .flat_map(|((device, config), sizes)| {
+                sizes.iter().map(move |(kernel_name, size)| {
+                    format!("{}\t{}\t{}\t{}\n", device, config, kernel_name, size)
+                })
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        fs::write(path, lines.concat())?;
+        Ok(())
+    }
+
+    /// Tuned sizes of the device and config of `gpu`.
+    pub fn get(&self, gpu: &GPU) -> NSEResult<Option<&LocalWorkSizes>> {
+        Ok(self.entries.get(&entry_key(gpu)?))
+    }
+
+    pub fn insert(&mut self, gpu: &GPU, sizes: LocalWorkSizes) -> NSEResult<()> {
+        self.entries.insert(entry_key(gpu)?, sizes);
+        Ok(())
+    }
+}
+
+/// Finds the fastest local work size of each kernel in `TUNED_KERNELS` on a GPU.
+/// Kernels are timed (Through their profiling events) on a separate, profiled context
+/// of the same device, so the state of the GPU is left untouched.
+pub struct Autotuner<'a> {
+    gpu: &'a mut GPU,
+    samples: usize,
+}
+
+// Run a kernel once, through the layer generation it is used by
+fn run_kernel(gpu: &mut GPU, kernel_name: &str) -> NSEResult<()> {
+    let replica_id = ReplicaId([0u8; 32]);
+    let expander = gpu.config.layer_position(LayerIndex::Expander(1));
+    let butterfly = gpu.config.layer_position(LayerIndex::Butterfly(1));
+    match kernel_name {
+        "generate_mask" => gpu.generate_mask_layer(replica_id, 0).map(|_| ()),
+        "generate_expander" => {
+            let layer_index = expander?;
+            gpu.generate_expander_layer(replica_id, 0, layer_index)
+                .map(|_| ())
+        }
+        "generate_butterfly" => {
+            let layer_index = butterfly?;
+            gpu.generate_butterfly_layer(replica_id, 0, layer_index)
+                .map(|_| ())
+        }
+        "generate_mask_batch" => gpu.generate_mask_layers().map(|_| ()),
+        "generate_expander_batch" => {
+            let layer_index = expander?;
+            gpu.generate_expander_layers(layer_index).map(|_| ())
+        }
+        "generate_butterfly_batch" => {
+            let layer_index = butterfly?;
+            gpu.generate_butterfly_layers(layer_index).map(|_| ())
+        }
+        "generate_streams" => {
+            let layer_index = expander?;
+            gpu.release_expander_streams();
+            gpu.generate_expander_layer(replica_id, 0, layer_index)
+                .map(|_| ())
+        }
+        // Run by every layer generation, and timed on its own through its profiling events
+        "generate_montgomery" => gpu.generate_mask_layer(replica_id, 0).map(|_| ()),
+        "generate_ordinary" => gpu.push_layer(&Layer(vec![Node::default(); gpu.leaf_count()])),
+        _ => Err(GPUError::Other(format!("Unknown kernel {}!", kernel_name)).into()),
+    }
+}
+
+impl<'a> Autotuner<'a> {
+    pub fn new(gpu: &'a mut GPU, samples: usize) -> Self {
+        assert!(samples > 0);
+        Self { gpu, samples }
+    }
+
+    // Device time of `samples` runs of a kernel, host transfers are not included
+    fn time_kernel(&self, tuner: &mut GPU, kernel_name: &str) -> NSEResult<Duration> {
+        tuner.reset_profile();
+        for _ in 0..self.samples {
+            run_kernel(tuner, kernel_name)?;
+        }
+        let report = tuner
+            .profile_report()?
+            .expect("Tuning contexts are profiled!");
+        report
+            .operations
+            .get(kernel_name)
+            .map(|timing| timing.total)
+            .ok_or_else(|| GPUError::Other(format!("{} was not run!", kernel_name)).into())
+    }
+
+    /// Times the candidate local work sizes of every kernel, and sets the fastest
+    /// ones on the GPU. Returns the chosen sizes.
+    pub fn tune(&mut self) -> NSEResult<LocalWorkSizes> {
+        let max_size = self.gpu.context().device().max_wg_size()?;
+
+        let ctx = GPUContext::from_shared_profiled(
+            self.gpu.context().shared_program(),
+            TreeOptions::Disabled,
+        )?;
+        let mut tuner = GPU::new(ctx, self.gpu.config.clone())?;
+        tuner.set_stream_tile_size(self.gpu.stream_tile_size())?;
+        let windows = (0..TUNING_BATCH_LEN as u64)
+            .map(|window_index| KeySpec {
+                replica_id: ReplicaId([0u8; 32]),
+                window_index,
+            })
+            .collect::<Vec<_>>();
+        tuner.start_batch(&windows)?;
+
+        // Expander and butterfly kernels need a previous layer, and the expander
+        // generates its parent bit-streams on first use, which should not be timed.
+        run_kernel(&mut tuner, "generate_mask")?;
+        run_kernel(&mut tuner, "generate_mask_batch")?;
+        if run_kernel(&mut tuner, "generate_expander").is_ok() {
+            run_kernel(&mut tuner, "generate_expander_batch")?;
+        }
+
+        let mut sizes = LocalWorkSizes::new();
+        for &kernel_name in TUNED_KERNELS.iter() {
+            // Sizes dropped by some launches of the kernel are not candidates
+            let global_size = self.gpu.kernel_global_size(kernel_name);
+            let candidates = CANDIDATE_SIZES
+                .iter()
+                .cloned()
+                .filter(|&size| size <= max_size && global_size % size == 0);
+            let mut best: Option<(Duration, usize)> = None;
+            for size in candidates {
+                tuner
+                    .context_mut()
+                    .set_local_work_size(kernel_name, Some(size));
+                // Sizes the kernel cannot be launched with (E.g. because of its
+                // register usage), and kernels of layers missing from the layer
+                // schedule, are skipped.
+                if let Ok(time) = self.time_kernel(&mut tuner, kernel_name) {
+                    if best.map_or(true, |(best_time, _)| time < best_time) {
+                        best = Some((time, size));
+                    }
+                }
+            }
+            let best_size = best.map(|(_, size)| size);
+            self.gpu
+                .context_mut()
+                .set_local_work_size(kernel_name, best_size);
+            if let Some(size) = best_size {
+                sizes.insert(kernel_name.to_string(), size);
+            }
+        }
+        Ok(sizes)
+    }
+}
+
+/// Sets the local work sizes persisted in `path` for the device and config of `gpu`,
+/// tuning them (And persisting the results) first if they are missing.
+pub fn load_or_tune<P: AsRef<Path>>(gpu: &mut GPU, path: P, samples: usize) -> NSEResult<()> {
+    let mut table = TuningTable::load(&path)?;
+    let sizes = match table.get(gpu)? {
+        Some(sizes) => sizes.clone(),
+        None => {
+            let sizes = Autotuner::new(gpu, samples).tune()?;
+            table.insert(gpu, sizes.clone())?;
+            table.save(&path)?;
+            sizes
+        }
+    };
+    for (kernel_name, &size) in sizes.iter() {
+        let global_size = gpu.kernel_global_size(kernel_name);
+        if global_size % size != 0 {
+            return Err(NSEError::InvalidTuningFile(format!(
+                "Local work size {} of {} does not divide its global work size {}!",
+                size, kernel_name, global_size
+            )));
+        }
+    }
+    for (kernel_name, &size) in sizes.iter() {
+        gpu.context_mut()
+            .set_local_work_size(kernel_name, Some(size));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, GPUContext, LabelingHash, TreeOptions};
+
+    fn test_config() -> Config {
+        Config::new(2, 512, 96, 4, 4, 3, LabelingHash::Sha256).unwrap()
+    }
+
+    #[test]
+    fn test_config_key() {
+        assert_eq!(
+            config_key(&test_config()),
+            "k=2;nodes=512;hash=sha256;layers=M,E96,E96,E96,B4,B4,B4"
+        );
+    }
+
+    #[test]
+    fn test_autotuner() {
+        let path = std::env::temp_dir().join("nse-test-tuning.tsv");
+        let _ = fs::remove_file(&path);
+
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        let replica_id = ReplicaId([1u8; 32]);
+        let expected = gpu.generate_mask_layer(replica_id, 123).unwrap();
+
+        load_or_tune(&mut gpu, &path, 2).unwrap();
+        let sizes = gpu.context().local_work_sizes().clone();
+        assert!(sizes
+            .values()
+            .all(|&size| test_config().num_nodes_window % size == 0));
+        assert_eq!(gpu.generate_mask_layer(replica_id, 123).unwrap(), expected);
+
+        let table = TuningTable::load(&path).unwrap();
+        assert_eq!(table.get(&gpu).unwrap(), Some(&sizes));
+
+        // Persisted sizes are used without tuning again
+        let ctx = GPUContext::default(test_config(), TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, test_config()).unwrap();
+        load_or_tune(&mut gpu, &path, 2).unwrap();
+        assert_eq!(gpu.context().local_work_sizes(), &sizes);
+
+        // Sizes which launches of the kernel would drop are rejected
+        let mut table = TuningTable::default();
+        let mut sizes = LocalWorkSizes::new();
+        sizes.insert(
+            "generate_mask".to_string(),
+            2 * test_config().num_nodes_window,
+        );
+        table.insert(&gpu, sizes).unwrap();
+        table.save(&path).unwrap();
+        assert!(load_or_tune(&mut gpu, &path, 2).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}